@@ -1,10 +1,20 @@
 use std::error::Error;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
 
 use clap::{Command, command};
 use colored::Colorize;
 
 use crate::common::PICTURA;
+use crate::doctor;
 use crate::gallery;
+use crate::gallery::{SyncOptions, SyncSummary};
+use crate::logger;
+use crate::migrate;
+use crate::tags;
+use crate::tags::TagChange;
+use crate::watch;
 use crate::config::Config;
 
 const INIT_CMD: &str = "init";
@@ -13,6 +23,31 @@ const INIT_CMD_ANIMATIONS_ARG: &str = "animations";
 const INIT_CMD_DARK_THEME_ARG: &str = "dark-theme";
 
 const SYNC_CMD: &str = "sync";
+const SYNC_CMD_JOBS_ARG: &str = "jobs";
+const SYNC_CMD_WATCH_ARG: &str = "watch";
+const SYNC_CMD_DRY_RUN_ARG: &str = "dry-run";
+const SYNC_CMD_JSON_ARG: &str = "json";
+
+const FIND_CMD: &str = "find";
+const FIND_CMD_COLOR_ARG: &str = "color";
+const FIND_CMD_LIMIT_ARG: &str = "limit";
+
+const DUPES_CMD: &str = "dupes";
+const DUPES_CMD_THRESHOLD_ARG: &str = "threshold";
+const DUPES_CMD_KEEP_HIGHEST_ARG: &str = "keep-highest";
+const DUPES_CMD_YES_ARG: &str = "yes";
+
+const DOCTOR_CMD: &str = "doctor";
+const DOCTOR_CMD_FIX_ARG: &str = "fix";
+
+const MIGRATE_CMD: &str = "migrate";
+
+const TAG_CMD: &str = "tag";
+const TAG_CMD_ADD_CMD: &str = "add";
+const TAG_CMD_REMOVE_CMD: &str = "remove";
+const TAG_CMD_LIST_CMD: &str = "list";
+const TAG_CMD_PICTURE_ARG: &str = "picture";
+const TAG_CMD_TAGS_ARG: &str = "tags";
 
 /// Initialize subcommands and args.
 fn init<'a>() -> Command<'a> {
@@ -45,7 +80,186 @@ fn init<'a>() -> Command<'a> {
         .subcommand(
             Command::new(SYNC_CMD)
                 .about("Sync the gallery with the filesystem")
+                .arg(
+                    clap::Arg::with_name(SYNC_CMD_JOBS_ARG)
+                        .help("Number of images to process in parallel (defaults to number of CPUs)")
+                        .long(SYNC_CMD_JOBS_ARG)
+                        .short(SYNC_CMD_JOBS_ARG.chars().next().unwrap())
+                        .takes_value(true)
+                        .value_name("N")
+                        .validator(|v| v.parse::<usize>())
+                )
+                .arg(
+                    clap::Arg::with_name(SYNC_CMD_WATCH_ARG)
+                        .help("Keep watching source directories, config and template, and sync on changes")
+                        .long(SYNC_CMD_WATCH_ARG)
+                        .short(SYNC_CMD_WATCH_ARG.chars().next().unwrap())
+                        .takes_value(false)
+                        .conflicts_with_all(&[SYNC_CMD_DRY_RUN_ARG, SYNC_CMD_JSON_ARG])
+                )
+                .arg(
+                    clap::Arg::with_name(SYNC_CMD_DRY_RUN_ARG)
+                        .help("List planned changes without making them")
+                        .long(SYNC_CMD_DRY_RUN_ARG)
+                        .short(SYNC_CMD_DRY_RUN_ARG.chars().next().unwrap())
+                        .takes_value(false)
+                )
+                .arg(
+                    clap::Arg::with_name(SYNC_CMD_JSON_ARG)
+                        .help("Print summary of the changes as JSON, logs go to stderr")
+                        .long(SYNC_CMD_JSON_ARG)
+                        .takes_value(false)
+                )
+        )
+        .subcommand(
+            Command::new(FIND_CMD)
+                .about("Find pictures by color")
+                .arg(
+                    clap::Arg::with_name(FIND_CMD_COLOR_ARG)
+                        .help("Color in HEX format, e.g. '#1e1e2e'")
+                        .long(FIND_CMD_COLOR_ARG)
+                        .short(FIND_CMD_COLOR_ARG.chars().next().unwrap())
+                        .takes_value(true)
+                        .required(true)
+                )
+                .arg(
+                    clap::Arg::with_name(FIND_CMD_LIMIT_ARG)
+                        .help("Maximum number of pictures to show")
+                        .long(FIND_CMD_LIMIT_ARG)
+                        .short(FIND_CMD_LIMIT_ARG.chars().next().unwrap())
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("20")
+                        .validator(|v| v.parse::<usize>())
+                )
         )
+        .subcommand(
+            Command::new(DUPES_CMD)
+                .about("Find duplicate and near-duplicate pictures")
+                .arg(
+                    clap::Arg::with_name(DUPES_CMD_THRESHOLD_ARG)
+                        .help("Maximum number of differing bits of perceptual hashes, out of 64 (defaults to config)")
+                        .long(DUPES_CMD_THRESHOLD_ARG)
+                        .short(DUPES_CMD_THRESHOLD_ARG.chars().next().unwrap())
+                        .takes_value(true)
+                        .value_name("N")
+                        .validator(|v| v.parse::<u32>())
+                )
+                .arg(
+                    clap::Arg::with_name(DUPES_CMD_KEEP_HIGHEST_ARG)
+                        .help("Remove all but the highest resolution copy of each picture")
+                        .long(DUPES_CMD_KEEP_HIGHEST_ARG)
+                        .short(DUPES_CMD_KEEP_HIGHEST_ARG.chars().next().unwrap())
+                        .takes_value(false)
+                )
+                .arg(
+                    clap::Arg::with_name(DUPES_CMD_YES_ARG)
+                        .help("Remove copies without asking for confirmation")
+                        .long(DUPES_CMD_YES_ARG)
+                        .short(DUPES_CMD_YES_ARG.chars().next().unwrap())
+                        .takes_value(false)
+                        .requires(DUPES_CMD_KEEP_HIGHEST_ARG)
+                )
+        )
+        .subcommand(
+            Command::new(DOCTOR_CMD)
+                .about("Check originals and derivatives of the gallery for problems")
+                .arg(
+                    clap::Arg::with_name(DOCTOR_CMD_FIX_ARG)
+                        .help("Repair found problems by regenerating or deleting files")
+                        .long(DOCTOR_CMD_FIX_ARG)
+                        .short(DOCTOR_CMD_FIX_ARG.chars().next().unwrap())
+                        .takes_value(false)
+                )
+        )
+        .subcommand(
+            Command::new(MIGRATE_CMD)
+                .about("Upgrade config and pictures files written by older versions")
+        )
+        .subcommand(
+            Command::new(TAG_CMD)
+                .about("Manage tags of pictures")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new(TAG_CMD_ADD_CMD)
+                        .about("Add tags to pictures")
+                        .arg(picture_arg().required(true))
+                        .arg(tags_arg())
+                )
+                .subcommand(
+                    Command::new(TAG_CMD_REMOVE_CMD)
+                        .about("Remove tags from pictures")
+                        .arg(picture_arg().required(true))
+                        .arg(tags_arg())
+                )
+                .subcommand(
+                    Command::new(TAG_CMD_LIST_CMD)
+                        .about("List tags of pictures or all tags of the gallery")
+                        .arg(picture_arg())
+                )
+        )
+}
+
+/// Picture selector arg shared by tag subcommands.
+fn picture_arg<'a>() -> clap::Arg<'a> {
+    clap::Arg::with_name(TAG_CMD_PICTURE_ARG)
+        .help("Picture id, name or glob pattern, e.g. 'Nature/*'")
+        .takes_value(true)
+}
+
+/// Tags arg shared by tag subcommands.
+fn tags_arg<'a>() -> clap::Arg<'a> {
+    clap::Arg::with_name(TAG_CMD_TAGS_ARG)
+        .help("Tags")
+        .takes_value(true)
+        .multiple_values(true)
+        .required(true)
+}
+
+/// Ask a yes or no question, no is the default.
+fn confirm(question: &str) -> io::Result<bool> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Print changes planned by a dry run of sync.
+fn print_plan(summary: &SyncSummary) {
+    if summary.is_empty() {
+        println!("Gallery is up to date");
+        return;
+    }
+
+    let print = |marker: colored::ColoredString, paths: &[PathBuf]| {
+        paths.iter().for_each(|path| println!("{} {}", marker, path.display()));
+    };
+
+    print("+".green().bold(), &summary.added);
+    print("~".yellow().bold(), &summary.updated);
+    summary.moved
+        .iter()
+        .for_each(|m| println!("{} {} -> {}", ">".cyan().bold(), m.from.display(), m.to.display()));
+    print("-".red().bold(), &summary.removed);
+    print("*".blue().bold(), &summary.migrated);
+    print("x".dimmed(), &summary.stale);
+    summary.failed
+        .iter()
+        .for_each(|failure| println!("{} {}: {}", "!".red().bold(), failure.path.display(), failure.error.replace('\n', ": ")));
+
+    println!(
+        "\n{} to add, {} to update, {} to move, {} to remove, {} to migrate, {} stale derivatives to delete, {} failed to read",
+        summary.added.len(),
+        summary.updated.len(),
+        summary.moved.len(),
+        summary.removed.len(),
+        summary.migrated.len(),
+        summary.stale.len(),
+        summary.failed.len(),
+    );
 }
 
 /// Run cmd
@@ -69,8 +283,156 @@ pub fn run() -> Result<(), Box<dyn Error>> {
 
             Ok(())
         }
-        Some((SYNC_CMD, _)) => {
-            gallery::sync()?;
+        Some((SYNC_CMD, sub_matches)) => {
+            let mut options = SyncOptions::default();
+
+            if sub_matches.is_present(SYNC_CMD_JOBS_ARG) {
+                options.jobs = sub_matches.value_of(SYNC_CMD_JOBS_ARG).unwrap().parse()?;
+            }
+
+            if sub_matches.is_present(SYNC_CMD_WATCH_ARG) {
+                return watch::watch(options.jobs);
+            }
+
+            let json = sub_matches.is_present(SYNC_CMD_JSON_ARG);
+            if json {
+                logger::log_to_stderr();
+            }
+
+            options.dry_run = sub_matches.is_present(SYNC_CMD_DRY_RUN_ARG);
+
+            let summary = gallery::sync(&options)?;
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&summary)?);
+            } else if options.dry_run {
+                print_plan(&summary);
+            }
+
+            Ok(())
+        }
+        Some((FIND_CMD, sub_matches)) => {
+            let color = sub_matches.value_of(FIND_CMD_COLOR_ARG).unwrap();
+            let limit = sub_matches.value_of(FIND_CMD_LIMIT_ARG).unwrap().parse()?;
+
+            for (distance, picture) in gallery::find(color, limit)? {
+                let path = picture.original.unwrap_or_default();
+                println!("{:>6.2}  {}", distance, path.display());
+            }
+
+            Ok(())
+        }
+        Some((DUPES_CMD, sub_matches)) => {
+            let threshold = match sub_matches.value_of(DUPES_CMD_THRESHOLD_ARG) {
+                Some(threshold) => Some(threshold.parse()?),
+                None => None,
+            };
+
+            let groups = gallery::find_duplicates(threshold)?;
+
+            if groups.is_empty() {
+                println!("No duplicates found");
+                return Ok(());
+            }
+
+            for group in &groups {
+                for (index, picture) in group.iter().enumerate() {
+                    let path = picture.original.clone().unwrap_or_default();
+                    let resolution = format!("{}x{}", picture.width, picture.height);
+                    let line = format!("{:>11}  {}", resolution, path.display());
+
+                    if index == 0 {
+                        println!("{}", line.green());
+                    } else {
+                        println!("{}", line);
+                    }
+                }
+                println!();
+            }
+
+            if sub_matches.is_present(DUPES_CMD_KEEP_HIGHEST_ARG) {
+                let count: usize = groups.iter().map(|group| group.len() - 1).sum();
+                let question = format!("Remove {} originals which are not in green?", count);
+
+                if sub_matches.is_present(DUPES_CMD_YES_ARG) || confirm(&question)? {
+                    gallery::keep_highest(&groups)?;
+                }
+            } else {
+                println!("Run with --{} to keep only the highest resolution copies (in green)", DUPES_CMD_KEEP_HIGHEST_ARG);
+            }
+
+            Ok(())
+        }
+        Some((DOCTOR_CMD, sub_matches)) => {
+            let problems = doctor::check()?;
+
+            if problems.is_empty() {
+                println!("No problems found");
+                return Ok(());
+            }
+
+            for problem in &problems {
+                println!("{}", problem.to_string().yellow());
+            }
+
+            println!();
+
+            if sub_matches.is_present(DOCTOR_CMD_FIX_ARG) {
+                doctor::fix(&problems)?;
+            } else {
+                println!("{} problems found, run with --{} to repair them", problems.len(), DOCTOR_CMD_FIX_ARG);
+            }
+
+            Ok(())
+        }
+        Some((MIGRATE_CMD, _)) => {
+            let migrated = migrate::migrate()?;
+
+            if migrated.is_empty() {
+                println!("Gallery is already at version {}", migrate::CURRENT_VERSION);
+            }
+
+            for file in migrated {
+                println!("Migrated {} from version {} to {}", file.path.display(), file.from, file.to);
+            }
+
+            Ok(())
+        }
+        Some((TAG_CMD, sub_matches)) => {
+            match sub_matches.subcommand() {
+                Some((TAG_CMD_LIST_CMD, list_matches)) => match list_matches.value_of(TAG_CMD_PICTURE_ARG) {
+                    Some(selector) => {
+                        for picture in tags::list(selector)? {
+                            let tags: Vec<String> = picture.all_tags().into_iter().collect();
+                            println!("{}: {}", picture.name.bold(), tags.join(", "));
+                        }
+                    }
+                    None => {
+                        for (tag, count) in tags::count()? {
+                            println!("{} ({})", tag, count);
+                        }
+                    }
+                },
+                Some((command, change_matches)) => {
+                    let selector = change_matches.value_of(TAG_CMD_PICTURE_ARG).unwrap();
+                    let tags: Vec<String> = change_matches
+                        .values_of(TAG_CMD_TAGS_ARG)
+                        .unwrap()
+                        .map(String::from)
+                        .collect();
+
+                    let change = if command == TAG_CMD_ADD_CMD {
+                        TagChange::Add(&tags)
+                    } else {
+                        TagChange::Remove(&tags)
+                    };
+
+                    let changed = tags::change(selector, change)?;
+                    println!("{} pictures changed", changed);
+                }
+                None => unreachable!("subcommand is required"),
+            }
+
             Ok(())
         }
         _ => {
@@ -0,0 +1,298 @@
+use rayon::prelude::*;
+
+use crate::gallery::Picture;
+
+/// Palette colors with weight below this are ignored when searching by color,
+/// so that a tiny detail does not make a whole picture match.
+const MIN_SEARCH_WEIGHT: f64 = 0.05;
+
+/// Color in CIE L*a*b* space (D65 white point).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lab {
+    pub l: f64,
+    pub a: f64,
+    pub b: f64,
+}
+
+impl Lab {
+    pub fn from_rgb(r: u8, g: u8, b: u8) -> Self {
+        // sRGB to linear RGB
+        let linear = |c: u8| {
+            let c = c as f64 / 255.0;
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        };
+
+        let (r, g, b) = (linear(r), linear(g), linear(b));
+
+        // linear RGB to XYZ, normalized by D65 white point
+        let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+        let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+        let z = (0.0193339 * r + 0.1191920 * g + 0.9503041 * b) / 1.08883;
+
+        let f = |t: f64| {
+            if t > 216.0 / 24389.0 {
+                t.cbrt()
+            } else {
+                (24389.0 / 27.0 * t + 16.0) / 116.0
+            }
+        };
+
+        let (fx, fy, fz) = (f(x), f(y), f(z));
+
+        Self {
+            l: 116.0 * fy - 16.0,
+            a: 500.0 * (fx - fy),
+            b: 200.0 * (fy - fz),
+        }
+    }
+
+    /// Parse color in HEX format, with or without leading `#`.
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let (r, g, b) = hex_to_rgb(hex)?;
+
+        Some(Self::from_rgb(r, g, b))
+    }
+}
+
+/// Parse color in HEX format, with or without leading `#`.
+pub fn hex_to_rgb(hex: &str) -> Option<(u8, u8, u8)> {
+    let hex = hex.strip_prefix('#').unwrap_or(hex);
+
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+
+    Some((
+        u8::from_str_radix(&hex[0..2], 16).ok()?,
+        u8::from_str_radix(&hex[2..4], 16).ok()?,
+        u8::from_str_radix(&hex[4..6], 16).ok()?,
+    ))
+}
+
+/// Perceptual color difference by CIEDE2000 formula.
+pub fn ciede2000(first: &Lab, second: &Lab) -> f64 {
+    let pow7 = |x: f64| x.powi(7);
+    let twenty_five_pow7 = pow7(25.0);
+
+    let c1 = first.a.hypot(first.b);
+    let c2 = second.a.hypot(second.b);
+    let c_mean = (c1 + c2) / 2.0;
+
+    let g = 0.5 * (1.0 - (pow7(c_mean) / (pow7(c_mean) + twenty_five_pow7)).sqrt());
+
+    let a1 = (1.0 + g) * first.a;
+    let a2 = (1.0 + g) * second.a;
+
+    let c1 = a1.hypot(first.b);
+    let c2 = a2.hypot(second.b);
+
+    let hue = |b: f64, a: f64| {
+        if a == 0.0 && b == 0.0 {
+            0.0
+        } else {
+            b.atan2(a).to_degrees().rem_euclid(360.0)
+        }
+    };
+
+    let h1 = hue(first.b, a1);
+    let h2 = hue(second.b, a2);
+
+    let delta_l = second.l - first.l;
+    let delta_c = c2 - c1;
+
+    let delta_h = if c1 * c2 == 0.0 {
+        0.0
+    } else if (h2 - h1).abs() <= 180.0 {
+        h2 - h1
+    } else if h2 - h1 > 180.0 {
+        h2 - h1 - 360.0
+    } else {
+        h2 - h1 + 360.0
+    };
+    let delta_h = 2.0 * (c1 * c2).sqrt() * (delta_h / 2.0).to_radians().sin();
+
+    let l_mean = (first.l + second.l) / 2.0;
+    let c_mean = (c1 + c2) / 2.0;
+
+    let h_mean = if c1 * c2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2) / 2.0
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0) / 2.0
+    } else {
+        (h1 + h2 - 360.0) / 2.0
+    };
+
+    let cos = |degrees: f64| degrees.to_radians().cos();
+
+    let t = 1.0
+        - 0.17 * cos(h_mean - 30.0)
+        + 0.24 * cos(2.0 * h_mean)
+        + 0.32 * cos(3.0 * h_mean + 6.0)
+        - 0.20 * cos(4.0 * h_mean - 63.0);
+
+    let delta_theta = 30.0 * (-((h_mean - 275.0) / 25.0).powi(2)).exp();
+    let r_c = 2.0 * (pow7(c_mean) / (pow7(c_mean) + twenty_five_pow7)).sqrt();
+
+    let s_l = 1.0 + 0.015 * (l_mean - 50.0).powi(2) / (20.0 + (l_mean - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * c_mean;
+    let s_h = 1.0 + 0.015 * c_mean * t;
+    let r_t = -(2.0 * delta_theta).to_radians().sin() * r_c;
+
+    let (l, c, h) = (delta_l / s_l, delta_c / s_c, delta_h / s_h);
+
+    (l * l + c * c + h * h + r_t * c * h).sqrt()
+}
+
+/// Palette of a picture as Lab colors with their weights.
+/// Falls back to the dominant color for pictures without a palette.
+pub fn palette_lab(picture: &Picture) -> Vec<(Lab, f64)> {
+    if picture.palette.is_empty() {
+        return Lab::from_hex(&picture.color)
+            .map(|lab| vec![(lab, 1.0)])
+            .unwrap_or_default();
+    }
+
+    picture.palette
+        .iter()
+        .filter_map(|color| Some((Lab::from_hex(&color.color)?, color.weight)))
+        .collect()
+}
+
+/// Distance from a color to the closest noticeable color of a palette.
+pub fn color_distance(target: &Lab, palette: &[(Lab, f64)]) -> f64 {
+    let distances = |min_weight: f64| {
+        palette
+            .iter()
+            .filter(|(_, weight)| *weight >= min_weight)
+            .map(|(lab, _)| ciede2000(target, lab))
+            .min_by(f64::total_cmp)
+    };
+
+    distances(MIN_SEARCH_WEIGHT)
+        .or_else(|| distances(0.0))
+        .unwrap_or(f64::INFINITY)
+}
+
+/// Symmetric distance between two palettes:
+/// each color is matched with the closest color of the other palette, weighted by its share.
+pub fn palette_distance(first: &[(Lab, f64)], second: &[(Lab, f64)]) -> f64 {
+    let directed = |from: &[(Lab, f64)], to: &[(Lab, f64)]| {
+        let total = from.iter().map(|(_, weight)| weight).sum::<f64>();
+        if total == 0.0 {
+            return f64::INFINITY;
+        }
+
+        from
+            .iter()
+            .map(|(lab, weight)| {
+                let closest = to
+                    .iter()
+                    .map(|(other, _)| ciede2000(lab, other))
+                    .min_by(f64::total_cmp)
+                    .unwrap_or(f64::INFINITY);
+
+                closest * weight
+            })
+            .sum::<f64>() / total
+    };
+
+    (directed(first, second) + directed(second, first)) / 2.0
+}
+
+/// For each picture find up to `count` other pictures with the closest palettes,
+/// as pairs of index and distance ordered by distance.
+pub fn similar_pictures(pictures: &[Picture], count: usize) -> Vec<Vec<(usize, f64)>> {
+    if count == 0 {
+        return vec![Vec::new(); pictures.len()];
+    }
+
+    let palettes: Vec<Vec<(Lab, f64)>> = pictures.iter().map(palette_lab).collect();
+
+    palettes
+        .par_iter()
+        .enumerate()
+        .map(|(index, palette)| {
+            let mut distances: Vec<(usize, f64)> = palettes
+                .iter()
+                .enumerate()
+                .filter(|(other, _)| *other != index)
+                .map(|(other, other_palette)| (other, palette_distance(palette, other_palette)))
+                .filter(|(_, distance)| distance.is_finite())
+                .collect();
+
+            distances.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+            distances.truncate(count);
+
+            distances
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// L*, a* and b* of a color
+    type Components = (f64, f64, f64);
+
+    /// Pairs from "The CIEDE2000 Color-Difference Formula: Implementation Notes,
+    /// Supplementary Test Data, and Mathematical Observations" by Sharma, Wu and Dalal.
+    const SHARMA: &[(Components, Components, f64)] = &[
+        ((50.0, 2.6772, -79.7751), (50.0, 0.0, -82.7485), 2.0425),
+        ((50.0, 3.1571, -77.2803), (50.0, 0.0, -82.7485), 2.8615),
+        ((50.0, 2.8361, -74.0200), (50.0, 0.0, -82.7485), 3.4412),
+        ((50.0, 0.0, 0.0), (50.0, -1.0, 2.0), 2.3669),
+        ((50.0, -1.0, 2.0), (50.0, 0.0, 0.0), 2.3669),
+        // mean hue and hue difference wrap around 0°/360°
+        ((50.0, 2.4900, -0.0010), (50.0, -2.4900, 0.0009), 7.1792),
+        ((50.0, 2.4900, -0.0010), (50.0, -2.4900, 0.0010), 7.1792),
+        ((50.0, 2.4900, -0.0010), (50.0, -2.4900, 0.0011), 7.2195),
+        ((50.0, 2.4900, -0.0010), (50.0, -2.4900, 0.0012), 7.2195),
+        ((50.0, -0.0010, 2.4900), (50.0, 0.0009, -2.4900), 4.8045),
+        ((50.0, -0.0010, 2.4900), (50.0, 0.0010, -2.4900), 4.8045),
+        ((50.0, -0.0010, 2.4900), (50.0, 0.0011, -2.4900), 4.7461),
+        ((50.0, 2.5, 0.0), (73.0, 25.0, -18.0), 27.1492),
+        ((50.0, 2.5, 0.0), (61.0, -5.0, 29.0), 22.8977),
+        ((50.0, 2.5, 0.0), (56.0, -27.0, -3.0), 31.9030),
+        ((50.0, 2.5, 0.0), (58.0, 24.0, 15.0), 19.4535),
+        ((50.0, 2.5, 0.0), (50.0, 3.1736, 0.5854), 1.0000),
+        ((60.2574, -34.0099, 36.2677), (60.4626, -34.1751, 39.4387), 1.2644),
+        ((22.7233, 20.0904, -46.6940), (23.0331, 14.9730, -42.5619), 2.0373),
+    ];
+
+    fn lab((l, a, b): Components) -> Lab {
+        Lab { l, a, b }
+    }
+
+    #[test]
+    fn matches_sharma_reference_data() {
+        for (first, second, expected) in SHARMA {
+            let distance = ciede2000(&lab(*first), &lab(*second));
+
+            assert!((distance - expected).abs() < 1e-4, "{first:?} {second:?}: {distance} != {expected}");
+        }
+    }
+
+    #[test]
+    fn is_symmetric() {
+        for (first, second, _) in SHARMA {
+            let there = ciede2000(&lab(*first), &lab(*second));
+            let back = ciede2000(&lab(*second), &lab(*first));
+
+            assert!((there - back).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn same_color_has_no_distance() {
+        let color = Lab::from_hex("#1e1e2e").unwrap();
+
+        assert_eq!(ciede2000(&color, &color), 0.0);
+    }
+}
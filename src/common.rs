@@ -1,7 +1,25 @@
-use std::path::PathBuf;
+use std::fs;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use image::ImageFormat;
 
 pub const PICTURA: &str = "pictura";
-pub const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png"];
+
+/// Image formats that can be used as wallpapers.
+pub const IMAGE_FORMATS: &[ImageFormat] = &[
+    ImageFormat::Jpeg,
+    ImageFormat::Png,
+    ImageFormat::WebP,
+    ImageFormat::Avif,
+    ImageFormat::Gif,
+    ImageFormat::Bmp,
+    ImageFormat::Tiff,
+];
+
+/// Number of bytes read from the beginning of a file to detect its format.
+const MAGIC_BYTES_LEN: u64 = 16;
 
 pub mod paths {
     use super::*;
@@ -14,12 +32,9 @@ pub mod paths {
         PathBuf::from("wallpapers")
     }
 
-    pub fn compressed() -> PathBuf {
-        pictura().join("compressed")
-    }
-
-    pub fn medium() -> PathBuf {
-        pictura().join("medium")
+    /// Directory of derivatives of the given size
+    pub fn derivatives(size: &str) -> PathBuf {
+        pictura().join(size)
     }
 
     pub fn config_file() -> PathBuf {
@@ -30,9 +45,29 @@ pub mod paths {
         pictura().join("pictures.toml")
     }
 
+    /// Custom template of the gallery page
+    pub fn template_file() -> PathBuf {
+        pictura().join("index.hbs")
+    }
+
     pub fn html_file() -> PathBuf {
         PathBuf::from("index.html")
     }
+
+    /// Lock file held while the gallery is written
+    pub fn lock_file() -> PathBuf {
+        pictura().join("lock")
+    }
+
+    /// Similar pictures found by the last render, reused while palettes stay the same
+    pub fn similar_cache() -> PathBuf {
+        pictura().join("similar.json")
+    }
+
+    /// Name of gitignore-style files listing wallpapers to skip
+    pub fn ignore_file() -> PathBuf {
+        PathBuf::from(format!(".{PICTURA}ignore"))
+    }
 }
 
 
@@ -42,4 +77,124 @@ pub fn capitalize(string: &str) -> String {
         None => String::new(),
         Some(f) => f.to_uppercase().collect::<String>() + c.as_str(),
     }
-}
\ No newline at end of file
+}
+
+
+/// Write a file through a temporary file next to it and a rename,
+/// so that a crash never leaves it partially written.
+pub fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Not a file: {}", path.display())))?;
+
+    let temp = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+
+    let written = File::create(&temp)
+        .and_then(|mut file| {
+            file.write_all(contents.as_ref())?;
+            file.sync_all()
+        })
+        .and_then(|()| fs::rename(&temp, path));
+
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+
+    written
+}
+
+/// Check if images of this format can be decoded.
+pub fn is_format_supported(format: ImageFormat) -> bool {
+    match format {
+        // decoding requires native dav1d library
+        ImageFormat::Avif => cfg!(feature = "avif"),
+        _ => IMAGE_FORMATS.contains(&format),
+    }
+}
+
+/// Check if file is an image of one of the given formats judging by its extension (case-insensitive).
+pub fn is_image(path: &Path, formats: &[ImageFormat]) -> bool {
+    ImageFormat::from_path(path)
+        .map(|format| formats.contains(&format))
+        .unwrap_or(false)
+}
+
+/// Detect image format by its magic bytes, ignoring the file extension.
+pub fn detect_format(path: &Path) -> io::Result<Option<ImageFormat>> {
+    let mut buffer = Vec::new();
+    File::open(path)?.take(MAGIC_BYTES_LEN).read_to_end(&mut buffer)?;
+
+    let format = match image::guess_format(&buffer) {
+        // RIFF is a generic container, make sure it holds WebP
+        Ok(ImageFormat::WebP) if buffer.get(8..12) != Some(b"WEBP") => None,
+        Ok(format) => Some(format),
+        Err(_) => None,
+    };
+
+    Ok(format)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    const JPEG: &[u8] = &[0xff, 0xd8, 0xff, 0xe0, 0x00, 0x10, b'J', b'F', b'I', b'F'];
+    const GIF: &[u8] = b"GIF89a\x01\0\x01\0";
+    const BMP: &[u8] = b"BM\x3a\0\0\0\0\0\0\0";
+    const TIFF: &[u8] = b"II*\0\x08\0\0\0";
+    const WEBP: &[u8] = b"RIFF\x24\0\0\0WEBPVP8 ";
+    const AVIF: &[u8] = b"\0\0\0\x1cftypavif";
+    const WAV: &[u8] = b"RIFF\x24\0\0\0WAVEfmt ";
+
+    /// Write contents to a temporary file with the given name and detect its format.
+    fn detect(name: &str, contents: &[u8]) -> Option<ImageFormat> {
+        let dir = std::env::temp_dir().join(format!("{PICTURA}-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+
+        let format = detect_format(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        format
+    }
+
+    #[test]
+    fn detects_format_by_magic_bytes() {
+        assert_eq!(detect("png.png", PNG), Some(ImageFormat::Png));
+        assert_eq!(detect("jpeg.jpg", JPEG), Some(ImageFormat::Jpeg));
+        assert_eq!(detect("gif.gif", GIF), Some(ImageFormat::Gif));
+        assert_eq!(detect("bmp.bmp", BMP), Some(ImageFormat::Bmp));
+        assert_eq!(detect("tiff.tiff", TIFF), Some(ImageFormat::Tiff));
+        assert_eq!(detect("webp.webp", WEBP), Some(ImageFormat::WebP));
+        assert_eq!(detect("avif.avif", AVIF), Some(ImageFormat::Avif));
+    }
+
+    #[test]
+    fn ignores_extension_when_detecting_format() {
+        assert_eq!(detect("png.jpg", PNG), Some(ImageFormat::Png));
+        assert_eq!(detect("jpeg.webp", JPEG), Some(ImageFormat::Jpeg));
+        assert_eq!(detect("webp", WEBP), Some(ImageFormat::WebP));
+    }
+
+    #[test]
+    fn rejects_non_images() {
+        assert_eq!(detect("text.png", b"definitely not an image"), None);
+        assert_eq!(detect("sound.webp", WAV), None);
+        assert_eq!(detect("empty.jpg", b""), None);
+    }
+
+    #[test]
+    fn matches_extensions_case_insensitively() {
+        assert!(is_image(Path::new("IMG.JPG"), IMAGE_FORMATS));
+        assert!(is_image(Path::new("forest.WebP"), IMAGE_FORMATS));
+        assert!(is_image(Path::new("scan.tif"), IMAGE_FORMATS));
+        assert!(!is_image(Path::new("forest.webp"), &[ImageFormat::Png]));
+        assert!(!is_image(Path::new("notes.txt"), IMAGE_FORMATS));
+        assert!(!is_image(Path::new("README"), IMAGE_FORMATS));
+    }
+}
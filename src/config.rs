@@ -1,12 +1,201 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use image::ImageFormat;
+use log::warn;
 use serde::{Deserialize, Serialize};
-use crate::common::capitalize;
+use crate::common::{capitalize, IMAGE_FORMATS, is_format_supported, paths};
+use crate::migrate;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
+    /// Format version of the config file, see `migrate`
+    pub(crate) version: u32,
     pub(crate) title: String,
     pub(crate) dark_theme_support: bool,
     pub(crate) animations: bool,
-    pub(crate) remote: Option<String>
+    pub(crate) remote: Option<String>,
+    /// Enabled wallpaper formats, e.g. "jpg", "png", "webp"
+    #[serde(default = "default_formats")]
+    pub(crate) formats: Vec<String>,
+    /// Follow symbolic links when looking for wallpapers
+    #[serde(default)]
+    pub(crate) follow_symlinks: bool,
+    /// Gitignore-style patterns of files in the source directories to skip
+    #[serde(default)]
+    pub(crate) exclude: Vec<String>,
+    /// Number of palette colors extracted from each picture
+    #[serde(default = "default_palette_size")]
+    pub(crate) palette_size: u8,
+    /// Number of pictures with similar colors listed for each picture, 0 to disable
+    #[serde(default = "default_similar_count")]
+    pub(crate) similar_count: usize,
+    /// Maximum number of differing bits of perceptual hashes of near-duplicate pictures, out of 64
+    #[serde(default = "default_duplicate_threshold")]
+    pub(crate) duplicate_threshold: u32,
+    /// Warn when sync adds a picture which duplicates an existing one
+    #[serde(default = "default_warn_duplicates")]
+    pub(crate) warn_duplicates: bool,
+    /// Number of processed images after which sync saves its progress, 0 to save only at the end
+    #[serde(default = "default_checkpoint_interval")]
+    pub(crate) checkpoint_interval: usize,
+    /// What to do with EXIF metadata of originals in derivatives
+    #[serde(default)]
+    pub(crate) metadata: MetadataPolicy,
+    /// Order of pictures in the gallery
+    #[serde(default)]
+    pub(crate) sort_by: SortKey,
+    /// Sort pictures in descending order, pictures without the sort key always go last
+    #[serde(default)]
+    pub(crate) sort_descending: bool,
+    /// Named sizes of derivative images, e.g. "compressed" and "medium"
+    #[serde(default = "default_derivatives")]
+    pub(crate) derivatives: Derivatives,
+    /// Width-stepped derivatives for responsive images
+    #[serde(default)]
+    pub(crate) srcset: Srcset,
+    /// Directories with wallpapers
+    #[serde(default = "default_sources")]
+    pub(crate) sources: Vec<Source>,
+}
+
+/// Directory with wallpapers.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Source {
+    /// Path of the directory, absolute or relative to the gallery root
+    pub(crate) path: PathBuf,
+    /// Category of wallpapers at the top of the directory, prepended to categories of nested ones
+    pub(crate) prefix: Option<String>,
+}
+
+/// Prefix of derivative size names generated for srcset
+pub const SRCSET_PREFIX: &str = "srcset-";
+
+pub type Derivatives = BTreeMap<String, DerivativeSize>;
+
+/// Size of a derivative image.
+/// Either `long_edge` or `max_width` and/or `max_height` are expected to be set.
+/// Derivatives are never upscaled.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DerivativeSize {
+    /// Maximum width in px
+    pub(crate) max_width: Option<u32>,
+    /// Maximum height in px
+    pub(crate) max_height: Option<u32>,
+    /// Length of the longest edge in px
+    pub(crate) long_edge: Option<u32>,
+    /// Output format, same as the original if not set
+    pub(crate) format: Option<OutputFormat>,
+    /// Encoding quality from 1 to 100, ignored for png
+    #[serde(default = "default_quality")]
+    pub(crate) quality: u8,
+    /// Additional format for browsers that do not support the output format
+    pub(crate) fallback: Option<OutputFormat>,
+}
+
+/// Widths of derivatives offered to browsers via `srcset`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Srcset {
+    /// Widths in px
+    pub(crate) widths: Vec<u32>,
+    /// Output format, same as the original if not set
+    pub(crate) format: Option<OutputFormat>,
+    /// Encoding quality from 1 to 100, ignored for png
+    #[serde(default = "default_quality")]
+    pub(crate) quality: u8,
+}
+
+impl Default for Srcset {
+    fn default() -> Self {
+        Self {
+            widths: vec![320, 640, 960, 1280, 1920],
+            format: Some(OutputFormat::Webp),
+            quality: default_quality(),
+        }
+    }
+}
+
+/// Format of derivative images.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Jpeg,
+    Webp,
+    Avif,
+    Png,
+}
+
+/// What to do with EXIF metadata of originals in derivatives.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum MetadataPolicy {
+    /// Derivatives carry no metadata
+    #[default]
+    Strip,
+    /// Keep metadata except GPS location
+    StripGps,
+    /// Keep all metadata
+    Keep,
+}
+
+/// Field of pictures to sort the gallery by.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    #[default]
+    Name,
+    /// Title from sidecar or embedded metadata, name if it is not set
+    Title,
+    Date,
+    Artist,
+    Copyright,
+    Camera,
+    Lens,
+    Source,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Png => "png",
+        }
+    }
+}
+
+impl DerivativeSize {
+    /// Get bounding box for a derivative of an image with given dimensions.
+    pub fn bounds(&self, width: u32, height: u32) -> (u32, u32) {
+        let (max_width, max_height) = match self.long_edge {
+            Some(edge) => (edge, edge),
+            None => (
+                self.max_width.unwrap_or(u32::MAX),
+                self.max_height.unwrap_or(u32::MAX),
+            ),
+        };
+
+        (max_width.min(width), max_height.min(height))
+    }
+
+    /// Get fallback format if it differs from the output format.
+    pub fn fallback(&self) -> Option<OutputFormat> {
+        self.fallback.filter(|fallback| Some(*fallback) != self.format)
+    }
+}
+
+impl Default for DerivativeSize {
+    fn default() -> Self {
+        Self {
+            max_width: None,
+            max_height: None,
+            long_edge: None,
+            format: None,
+            quality: default_quality(),
+            fallback: None,
+        }
+    }
 }
 
 impl Default for Config {
@@ -17,10 +206,184 @@ impl Default for Config {
         };
 
         Self {
+            version: migrate::CURRENT_VERSION,
             title: format!("{}'s Wallpapers", username),
             dark_theme_support: false,
             animations: false,
-            remote: None
+            remote: None,
+            formats: default_formats(),
+            follow_symlinks: false,
+            exclude: Vec::new(),
+            palette_size: default_palette_size(),
+            similar_count: default_similar_count(),
+            duplicate_threshold: default_duplicate_threshold(),
+            warn_duplicates: default_warn_duplicates(),
+            checkpoint_interval: default_checkpoint_interval(),
+            metadata: MetadataPolicy::default(),
+            sort_by: SortKey::default(),
+            sort_descending: false,
+            derivatives: default_derivatives(),
+            srcset: Srcset::default(),
+            sources: default_sources(),
+        }
+    }
+}
+
+impl Config {
+    /// Get all derivative sizes to generate: named ones and srcset widths.
+    pub fn sizes(&self) -> Derivatives {
+        let srcset = self.srcset.widths
+            .iter()
+            .map(|width| {
+                let size = DerivativeSize {
+                    max_width: Some(*width),
+                    format: self.srcset.format,
+                    quality: self.srcset.quality,
+                    ..Default::default()
+                };
+
+                (format!("{SRCSET_PREFIX}{width}"), size)
+            });
+
+        self.derivatives
+            .clone()
+            .into_iter()
+            .chain(srcset)
+            .collect()
+    }
+
+    /// Get source directories with paths resolved against the gallery root.
+    pub fn sources(&self, gallery_root: &Path) -> Vec<Source> {
+        self.sources
+            .iter()
+            .map(|source| Source {
+                path: gallery_root.join(&source.path),
+                prefix: source.prefix.clone().filter(|prefix| !prefix.is_empty()),
+            })
+            .collect()
+    }
+
+    /// Check that names of derivative sizes can be used as directory names inside `.pictura`:
+    /// only lowercase letters, digits, `-` and `_`, not taken by files of pictura or by srcset sizes.
+    pub fn validate(&self) -> Result<(), String> {
+        let internal = [
+            paths::config_file(),
+            paths::pictures_file(),
+            paths::template_file(),
+            paths::lock_file(),
+            paths::similar_cache(),
+        ];
+
+        for name in self.derivatives.keys() {
+            let valid = !name.is_empty()
+                && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+
+            if !valid {
+                return Err(format!("Invalid derivative size name `{name}`, use only a-z, 0-9, `-` and `_`"));
+            }
+
+            let reserved = name.starts_with(SRCSET_PREFIX)
+                || internal.iter().any(|path| path.file_name().is_some_and(|file_name| file_name == name.as_str()));
+
+            if reserved {
+                return Err(format!("Derivative size name `{name}` is reserved, choose another one"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get enabled wallpaper formats which can be decoded.
+    pub fn image_formats(&self) -> Vec<ImageFormat> {
+        self.formats
+            .iter()
+            .filter_map(|name| {
+                match ImageFormat::from_extension(name) {
+                    Some(format) if is_format_supported(format) => Some(format),
+                    _ => {
+                        warn!("Unsupported image format: {}", name);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+fn default_formats() -> Vec<String> {
+    IMAGE_FORMATS
+        .iter()
+        .filter(|format| is_format_supported(**format))
+        .map(|format| format.extensions_str()[0].to_string())
+        .collect()
+}
+
+fn default_sources() -> Vec<Source> {
+    vec![Source { path: paths::wallpapers(), prefix: None }]
+}
+
+fn default_derivatives() -> Derivatives {
+    BTreeMap::from([
+        ("compressed".to_string(), DerivativeSize {
+            long_edge: Some(480),
+            format: Some(OutputFormat::Webp),
+            quality: 75,
+            ..Default::default()
+        }),
+        ("medium".to_string(), DerivativeSize {
+            long_edge: Some(1280),
+            format: Some(OutputFormat::Webp),
+            ..Default::default()
+        }),
+    ])
+}
+
+fn default_quality() -> u8 {
+    80
+}
+
+fn default_palette_size() -> u8 {
+    5
+}
+
+fn default_similar_count() -> usize {
+    5
+}
+
+fn default_duplicate_threshold() -> u32 {
+    10
+}
+
+fn default_warn_duplicates() -> bool {
+    true
+}
+
+fn default_checkpoint_interval() -> usize {
+    100
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_size(name: &str) -> Config {
+        let mut config = Config::default();
+        config.derivatives.insert(name.to_string(), DerivativeSize { long_edge: Some(480), ..Default::default() });
+
+        config
+    }
+
+    #[test]
+    fn accepts_default_and_simple_size_names() {
+        assert!(Config::default().validate().is_ok());
+        assert!(with_size("large_2x").validate().is_ok());
+        assert!(with_size("thumb-sm").validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_size_names_outside_pictura_dir_or_taken() {
+        for name in ["../x", "a/b", "Large", "", "similar.json", "config.toml", "lock", "srcset-320"] {
+            assert!(with_size(name).validate().is_err(), "{name}");
         }
     }
 }
@@ -0,0 +1,210 @@
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use log::{info, warn};
+use rayon::prelude::*;
+
+use crate::common::paths;
+use crate::gallery;
+use crate::gallery::{get_pictura_root_dir, hash_file, GalleryLock, Picture, SyncOptions};
+
+/// Problem of the gallery found by `check`.
+#[derive(Debug)]
+pub enum Problem {
+    /// Original of a picture is gone
+    MissingOriginal(PathBuf),
+    /// Original was edited after its derivatives were generated
+    ModifiedOriginal(PathBuf),
+    /// Derivative of a picture is gone
+    MissingDerivative(PathBuf),
+    /// Derivative which can't be read as an image, e.g. because it was written partially
+    CorruptDerivative(PathBuf),
+    /// File in a derivatives directory which no picture uses
+    OrphanDerivative(PathBuf),
+    /// Id shared by pictures which list the same original or share derivatives, with their originals
+    DuplicateId(String, Vec<PathBuf>),
+    /// Id which is neither a content hash nor a numeric id of an older version, with the original
+    InvalidId(String, PathBuf),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::MissingOriginal(path) => write!(f, "missing original: {}", path.display()),
+            Problem::ModifiedOriginal(path) => write!(f, "modified original: {}", path.display()),
+            Problem::MissingDerivative(path) => write!(f, "missing derivative: {}", path.display()),
+            Problem::CorruptDerivative(path) => write!(f, "corrupt derivative: {}", path.display()),
+            Problem::OrphanDerivative(path) => write!(f, "orphan derivative: {}", path.display()),
+            Problem::DuplicateId(id, originals) => {
+                let originals: Vec<String> = originals.iter().map(|path| path.display().to_string()).collect();
+                write!(f, "duplicate id {}: {}", id, originals.join(", "))
+            }
+            Problem::InvalidId(id, original) => write!(f, "invalid id {}: {}", id, original.display()),
+        }
+    }
+}
+
+/// Check every picture against its original and derivatives with the current config,
+/// and look for files in derivatives directories which no picture uses.
+pub fn check() -> Result<Vec<Problem>, Box<dyn Error>> {
+    let gallery_root = get_pictura_root_dir()?;
+    let config = gallery::read_config(&gallery_root)?;
+    let sizes = config.sizes();
+    let sources = config.sources(&gallery_root);
+
+    let mut pictures = gallery::read_pictures(&gallery_root)?.pictures.unwrap_or_default();
+
+    for picture in pictures.iter_mut() {
+        // paths of derivatives which are no longer in config are reported as orphans
+        picture.setup_paths(&sizes, &sources)?;
+    }
+
+    let mut problems: Vec<Problem> = pictures
+        .par_iter()
+        .flat_map_iter(check_picture)
+        .collect();
+
+    let mut by_id: BTreeMap<&str, Vec<&Picture>> = BTreeMap::new();
+    for picture in &pictures {
+        by_id.entry(&picture.id).or_default().push(picture);
+    }
+
+    for (id, same) in by_id.into_iter().filter(|(_, same)| same.len() > 1) {
+        let originals: Vec<PathBuf> = same.iter().map(|picture| picture.original.clone().unwrap_or_default()).collect();
+
+        if has_shared_files(&same) {
+            problems.push(Problem::DuplicateId(id.to_string(), originals));
+        } else {
+            // byte-identical copies in different places are fine, each has its own derivatives
+            let originals: Vec<String> = originals.iter().map(|path| path.display().to_string()).collect();
+            info!("Identical copies: {}", originals.join(", "));
+        }
+    }
+
+    let used: HashSet<&PathBuf> = pictures
+        .iter()
+        .flat_map(|picture| picture.derivatives.values().chain(picture.fallbacks.values()))
+        .collect();
+
+    for size in sizes.keys() {
+        let dir = gallery_root.join(paths::derivatives(size));
+
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to read directory: {}\n{}", dir.display(), e);
+                continue;
+            }
+        };
+
+        let mut orphans: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_file()))
+            .map(|entry| entry.path())
+            .filter(|path| !used.contains(path))
+            .collect();
+
+        orphans.sort();
+        problems.extend(orphans.into_iter().map(Problem::OrphanDerivative));
+    }
+
+    Ok(problems)
+}
+
+/// Check if pictures with the same id list the same original or share a derivative.
+fn has_shared_files(pictures: &[&Picture]) -> bool {
+    let mut originals = HashSet::new();
+    let mut derivatives = HashSet::new();
+
+    pictures.iter().any(|picture| {
+        !originals.insert(&picture.original)
+            || !picture.derivatives.values().chain(picture.fallbacks.values()).all(|path| derivatives.insert(path))
+    })
+}
+
+/// Check original and derivatives of a picture.
+/// Only headers of derivatives are read, while originals are hashed completely.
+fn check_picture(picture: &Picture) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let original = picture.original.clone().unwrap_or_default();
+
+    if picture.has_legacy_id() && picture.id.parse::<u32>().is_err() {
+        problems.push(Problem::InvalidId(picture.id.clone(), original.clone()));
+    }
+
+    if !original.exists() {
+        problems.push(Problem::MissingOriginal(original));
+        return problems;
+    }
+
+    match hash_file(&original) {
+        Ok(id) if id != picture.id && !picture.has_legacy_id() => problems.push(Problem::ModifiedOriginal(original)),
+        Ok(_) => {}
+        Err(e) => warn!("Failed to read image: {}\n{}", original.display(), e),
+    }
+
+    for path in picture.derivatives.values().chain(picture.fallbacks.values()) {
+        if !path.exists() {
+            problems.push(Problem::MissingDerivative(path.clone()));
+        } else if image::image_dimensions(path).is_err() {
+            problems.push(Problem::CorruptDerivative(path.clone()));
+        }
+    }
+
+    problems
+}
+
+/// Fix problems found by `check`. Orphan and corrupt derivatives are deleted,
+/// pictures listed more than once are left once, and then the gallery is synced,
+/// which regenerates missing and shared derivatives, processes modified originals again,
+/// replaces invalid ids and removes pictures without originals.
+pub fn fix(problems: &[Problem]) -> Result<(), Box<dyn Error>> {
+    let gallery_root = get_pictura_root_dir()?;
+    let _lock = GalleryLock::acquire(&gallery_root)?;
+    let mut has_duplicates = false;
+    let mut modified: HashSet<&PathBuf> = HashSet::new();
+
+    for problem in problems {
+        match problem {
+            Problem::OrphanDerivative(path) | Problem::CorruptDerivative(path) => {
+                if let Err(e) = fs::remove_file(path) {
+                    warn!("Failed to remove file: {}\n{}", path.display(), e);
+                }
+            }
+            Problem::DuplicateId(..) => {
+                has_duplicates = true;
+            }
+            Problem::ModifiedOriginal(path) => {
+                modified.insert(path);
+            }
+            _ => {}
+        }
+    }
+
+    if has_duplicates || !modified.is_empty() {
+        let config = gallery::read_config(&gallery_root)?;
+        let pictures = gallery::read_pictures(&gallery_root)?.pictures.unwrap_or_default();
+
+        // the same original listed twice, e.g. after an interrupted sync
+        let mut seen = HashSet::new();
+        let mut pictures: Vec<Picture> = pictures
+            .into_iter()
+            .filter(|picture| picture.original.is_none() || seen.insert(picture.original.clone()))
+            .collect();
+
+        // sync only hashes originals whose size or modification time has changed
+        pictures
+            .iter_mut()
+            .filter(|picture| picture.original.as_ref().is_some_and(|original| modified.contains(original)))
+            .for_each(|picture| picture.modified = 0);
+
+        gallery::write_pictures(&gallery_root, &config, pictures)?;
+    }
+
+    gallery::sync(&SyncOptions::default())?;
+
+    Ok(())
+}
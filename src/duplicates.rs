@@ -0,0 +1,151 @@
+use image::imageops::FilterType;
+
+use crate::gallery::Picture;
+
+/// Hashes with fewer set or unset bits than this come from flat or smooth images,
+/// which all hash alike no matter their colors, so they are never taken for duplicates.
+const MIN_HASH_BITS: u32 = 4;
+
+/// Maximum relative difference of aspect ratios of duplicates, resized copies keep the ratio.
+const MAX_ASPECT_RATIO_DIFFERENCE: f64 = 0.02;
+
+/// Compute difference hash (dHash) of an image.
+/// Each bit tells whether a pixel of a 9x8 grayscale thumbnail is brighter than its right neighbour,
+/// so the hash survives resizing, re-encoding and slight color changes.
+pub fn dhash(img: &image::DynamicImage) -> u64 {
+    let sample = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if sample.get_pixel(x, y)[0] > sample.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+
+    hash
+}
+
+/// Number of differing bits of two perceptual hashes in HEX format.
+pub fn distance(first: &str, second: &str) -> Option<u32> {
+    let first = u64::from_str_radix(first, 16).ok()?;
+    let second = u64::from_str_radix(second, 16).ok()?;
+
+    Some((first ^ second).count_ones())
+}
+
+/// Check if a perceptual hash in HEX format tells anything about the picture.
+fn is_informative(hash: &str) -> bool {
+    u64::from_str_radix(hash, 16)
+        .is_ok_and(|hash| (MIN_HASH_BITS..=64 - MIN_HASH_BITS).contains(&hash.count_ones()))
+}
+
+/// Check if two pictures look the same within the threshold.
+/// Pictures with different aspect ratios or flat pictures are never the same.
+pub fn is_duplicate(first: &Picture, second: &Picture, threshold: u32) -> bool {
+    let ratio = |picture: &Picture| picture.width as f64 / picture.height.max(1) as f64;
+    let (first_ratio, second_ratio) = (ratio(first), ratio(second));
+
+    if (first_ratio - second_ratio).abs() > MAX_ASPECT_RATIO_DIFFERENCE * first_ratio.max(second_ratio) {
+        return false;
+    }
+
+    match (&first.phash, &second.phash) {
+        (Some(first), Some(second)) if is_informative(first) && is_informative(second) => {
+            distance(first, second).is_some_and(|d| d <= threshold)
+        }
+        _ => false,
+    }
+}
+
+/// Group pictures which look the same within the threshold as the highest resolution copy of the group.
+/// Returns groups of indices with at least two pictures,
+/// each ordered from the highest resolution copy to the lowest.
+pub fn find_groups(pictures: &[Picture], threshold: u32) -> Vec<Vec<usize>> {
+    let mut order: Vec<usize> = (0..pictures.len()).collect();
+    order.sort_by_key(|&index| {
+        let picture = &pictures[index];
+
+        std::cmp::Reverse((picture.width as u64 * picture.height as u64, picture.size))
+    });
+
+    // every member is compared with the head, so that A ~ B and B ~ C doesn't group A with a distant C
+    let mut grouped = vec![false; pictures.len()];
+    let mut groups = Vec::new();
+
+    for (position, &head) in order.iter().enumerate() {
+        if grouped[head] {
+            continue;
+        }
+
+        let mut group = vec![head];
+        for &other in &order[position + 1..] {
+            if !grouped[other] && is_duplicate(&pictures[head], &pictures[other], threshold) {
+                grouped[other] = true;
+                group.push(other);
+            }
+        }
+
+        if group.len() > 1 {
+            grouped[head] = true;
+            groups.push(group);
+        }
+    }
+
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn picture(phash: &str, width: u32, height: u32) -> Picture {
+        toml::from_str(&format!(
+            r#"
+            name = "{phash}"
+            extension = "png"
+            width = {width}
+            height = {height}
+            color = "000000"
+            id = "{phash}"
+            phash = "{phash}"
+            "#
+        )).unwrap()
+    }
+
+    #[test]
+    fn flat_pictures_are_not_duplicates() {
+        let black = picture("0000000000000000", 1920, 1080);
+        let red = picture("0000000000000000", 1920, 1080);
+        let gradient = picture("ffffffffffffffff", 1920, 1080);
+        let almost_flat = picture("0000000000000100", 1920, 1080);
+
+        assert!(!is_duplicate(&black, &red, 10));
+        assert!(!is_duplicate(&gradient, &gradient, 10));
+        assert!(!is_duplicate(&black, &almost_flat, 10));
+    }
+
+    #[test]
+    fn resized_copy_is_duplicate() {
+        let original = picture("f0e1d2c3b4a59687", 3840, 2160);
+        let resized = picture("f0e1d2c3b4a59686", 1280, 720);
+        let cropped = picture("f0e1d2c3b4a59687", 1080, 1080);
+
+        assert!(is_duplicate(&original, &resized, 10));
+        assert!(!is_duplicate(&original, &cropped, 10));
+    }
+
+    #[test]
+    fn members_are_compared_with_the_head() {
+        // each next one differs from the previous one by 6 bits, from the first one by 12
+        let pictures = [
+            picture("f0e1d2c3b4a59687", 3840, 2160),
+            picture("f0e1d2c3b4a5903f", 2560, 1440),
+            picture("f0e1d2c3b4a5603c", 1920, 1080),
+        ];
+
+        assert_eq!(find_groups(&pictures, 10), vec![vec![0, 1]]);
+    }
+}
@@ -0,0 +1,354 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::path::Path;
+
+use image::{DynamicImage, GenericImageView, ImageError, ImageFormat, ImageResult};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::{WebPEncoder, WebPQuality};
+use image::error::{UnsupportedError, UnsupportedErrorKind};
+use log::warn;
+
+use crate::common::write_atomic;
+use crate::config::OutputFormat;
+
+const JPEG_EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+/// Largest EXIF data which fits into a single jpeg APP1 segment along with its length and header.
+const JPEG_EXIF_MAX_SIZE: usize = u16::MAX as usize - 2 - JPEG_EXIF_HEADER.len();
+
+/// Save an image in the given format.
+/// If format is not set, it is guessed from the path extension.
+/// Partially written file is removed on failure.
+pub fn save(img: &DynamicImage, path: &Path, format: Option<OutputFormat>, quality: u8) -> ImageResult<()> {
+    let format = match format {
+        Some(format) => format,
+        None => return img.save(path),
+    };
+
+    if !is_output_supported(format) {
+        return Err(ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+            ImageFormat::Avif.into(),
+            UnsupportedErrorKind::Format(ImageFormat::Avif.into()),
+        )));
+    }
+
+    let quality = quality.clamp(1, 100);
+    let writer = BufWriter::new(File::create(path)?);
+
+    let result = match format {
+        // jpeg has no alpha channel
+        OutputFormat::Jpeg => DynamicImage::ImageRgb8(img.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(writer, quality)),
+        OutputFormat::Webp => with_8bit_color(img).write_with_encoder(webp_encoder(writer, quality)),
+        OutputFormat::Avif => save_avif(img, writer, quality),
+        OutputFormat::Png => with_8bit_color(img).write_with_encoder(PngEncoder::new(writer)),
+    };
+
+    if result.is_err() {
+        let _ = fs::remove_file(path);
+    }
+
+    result
+}
+
+/// Check if images can be encoded in this format.
+pub fn is_output_supported(format: OutputFormat) -> bool {
+    // avif encoding requires nasm at build time
+    format != OutputFormat::Avif || cfg!(feature = "avif")
+}
+
+/// Embed EXIF metadata (raw TIFF structure) into an encoded jpeg, png or webp image.
+/// Images in other formats are left as is, as well as jpeg images if metadata is too large for them.
+pub fn embed_exif(path: &Path, img: &DynamicImage, exif: &[u8]) -> io::Result<()> {
+    let data = fs::read(path)?;
+
+    let data = match image::guess_format(&data) {
+        Ok(ImageFormat::Jpeg) if exif.len() > JPEG_EXIF_MAX_SIZE => {
+            warn!("Metadata is too large to be kept in {}, skipping it", path.display());
+            return Ok(());
+        }
+        Ok(ImageFormat::Jpeg) => embed_exif_jpeg(data, exif),
+        Ok(ImageFormat::Png) => embed_exif_png(data, exif),
+        Ok(ImageFormat::WebP) => embed_exif_webp(data, img, exif),
+        _ => return Ok(()),
+    };
+
+    match data {
+        Some(data) => write_atomic(path, data),
+        None => Err(io::Error::other(format!("Failed to embed metadata into {}", path.display()))),
+    }
+}
+
+/// Insert APP1 segment after SOI and JFIF segment.
+fn embed_exif_jpeg(data: Vec<u8>, exif: &[u8]) -> Option<Vec<u8>> {
+    let length = u16::try_from(2 + JPEG_EXIF_HEADER.len() + exif.len()).ok()?;
+
+    let mut position = 2;
+    if data.get(2..4)? == [0xFF, 0xE0] {
+        position += 2 + u16::from_be_bytes([*data.get(4)?, *data.get(5)?]) as usize;
+    }
+
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend_from_slice(&length.to_be_bytes());
+    segment.extend_from_slice(JPEG_EXIF_HEADER);
+    segment.extend_from_slice(exif);
+
+    let mut data = data;
+    data.splice(position..position, segment);
+
+    Some(data)
+}
+
+/// Insert eXIf chunk after IHDR chunk, which is always the first one.
+fn embed_exif_png(data: Vec<u8>, exif: &[u8]) -> Option<Vec<u8>> {
+    // signature, IHDR length and type, 13 bytes of IHDR data and crc
+    const POSITION: usize = 8 + 8 + 13 + 4;
+
+    if data.get(12..16)? != b"IHDR" {
+        return None;
+    }
+
+    let chunk = png_chunk(exif, b"eXIf");
+    let mut data = data;
+    data.splice(POSITION..POSITION, chunk);
+
+    Some(data)
+}
+
+/// Append EXIF chunk, converting the file to extended format if needed.
+fn embed_exif_webp(data: Vec<u8>, img: &DynamicImage, exif: &[u8]) -> Option<Vec<u8>> {
+    const EXIF_FLAG: u8 = 0x08;
+    const ALPHA_FLAG: u8 = 0x10;
+
+    let mut data = match data.get(12..16)? {
+        b"VP8X" => data,
+        b"VP8 " | b"VP8L" => {
+            let (width, height) = img.dimensions();
+            let flags = if img.color().has_alpha() { ALPHA_FLAG } else { 0 };
+
+            let mut vp8x = vec![flags, 0, 0, 0];
+            vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+            vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+
+            let mut extended = data[..12].to_vec();
+            extended.extend_from_slice(b"VP8X");
+            extended.extend_from_slice(&(vp8x.len() as u32).to_le_bytes());
+            extended.extend_from_slice(&vp8x);
+            extended.extend_from_slice(&data[12..]);
+            extended
+        }
+        _ => return None,
+    };
+
+    data[20] |= EXIF_FLAG;
+
+    data.extend_from_slice(b"EXIF");
+    data.extend_from_slice(&(exif.len() as u32).to_le_bytes());
+    data.extend_from_slice(exif);
+    // chunks are padded to even size
+    if exif.len() % 2 == 1 {
+        data.push(0);
+    }
+
+    let riff_size = (data.len() - 8) as u32;
+    data[4..8].copy_from_slice(&riff_size.to_le_bytes());
+
+    Some(data)
+}
+
+/// Build a png chunk: big endian length, type, data and crc of type and data.
+fn png_chunk(data: &[u8], kind: &[u8; 4]) -> Vec<u8> {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(kind);
+    hasher.update(data);
+
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(&hasher.finalize().to_be_bytes());
+
+    chunk
+}
+
+/// Create webp encoder, quality of 100 means lossless.
+// lossy webp is deprecated in image crate, but it is the whole point of a derivative
+#[allow(deprecated)]
+fn webp_encoder(writer: BufWriter<File>, quality: u8) -> WebPEncoder<BufWriter<File>> {
+    let quality = if quality == 100 {
+        WebPQuality::lossless()
+    } else {
+        WebPQuality::lossy(quality)
+    };
+
+    WebPEncoder::new_with_quality(writer, quality)
+}
+
+#[cfg(feature = "avif")]
+fn save_avif(img: &DynamicImage, writer: BufWriter<File>, quality: u8) -> ImageResult<()> {
+    use image::codecs::avif::AvifEncoder;
+
+    // speed 6 is a reasonable tradeoff between encoding time and file size
+    with_8bit_color(img).write_with_encoder(AvifEncoder::new_with_speed_quality(writer, 6, quality))
+}
+
+#[cfg(not(feature = "avif"))]
+fn save_avif(_img: &DynamicImage, _writer: BufWriter<File>, _quality: u8) -> ImageResult<()> {
+    unreachable!("avif output is checked by is_output_supported")
+}
+
+/// Convert an image to 8-bit RGB or RGBA, which all encoders accept.
+fn with_8bit_color(img: &DynamicImage) -> DynamicImage {
+    match img {
+        DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => img.clone(),
+        _ if img.color().has_alpha() => DynamicImage::ImageRgba8(img.to_rgba8()),
+        _ => DynamicImage::ImageRgb8(img.to_rgb8()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use exif::{Field, In, Tag, Value};
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    use super::*;
+
+    /// Build EXIF data with the given artist.
+    fn exif(artist: &str) -> Vec<u8> {
+        let field = Field {
+            tag: Tag::Artist,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![artist.as_bytes().to_vec()]),
+        };
+
+        let mut writer = exif::experimental::Writer::new();
+        writer.push_field(&field);
+
+        let mut buf = Cursor::new(Vec::new());
+        writer.write(&mut buf, false).unwrap();
+
+        buf.into_inner()
+    }
+
+    /// Read the artist back from an encoded image.
+    fn artist(data: &[u8]) -> Option<String> {
+        let exif = exif::Reader::new()
+            .read_from_container(&mut Cursor::new(data))
+            .ok()?;
+        let field = exif.get_field(Tag::Artist, In::PRIMARY)?;
+
+        Some(field.display_value().to_string())
+    }
+
+    fn encode(img: &DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut buf = Cursor::new(Vec::new());
+        img.write_to(&mut buf, format).unwrap();
+
+        buf.into_inner()
+    }
+
+    fn rgb() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(3, 2, Rgb([200, 100, 50])))
+    }
+
+    #[test]
+    fn jpeg_segment_is_inserted_after_jfif() {
+        let data = encode(&rgb(), ImageFormat::Jpeg);
+        assert_eq!(data[2..4], [0xFF, 0xE0]);
+
+        let jfif_end = 4 + u16::from_be_bytes([data[4], data[5]]) as usize;
+        let embedded = embed_exif_jpeg(data.clone(), &exif("Ansel")).unwrap();
+
+        assert_eq!(embedded[..jfif_end], data[..jfif_end]);
+        assert_eq!(embedded[jfif_end..jfif_end + 2], [0xFF, 0xE1]);
+        assert_eq!(artist(&embedded).as_deref(), Some("\"Ansel\""));
+        assert!(image::load_from_memory(&embedded).is_ok());
+    }
+
+    #[test]
+    fn jpeg_segment_is_inserted_after_soi_without_jfif() {
+        // SOI directly followed by EOI
+        let embedded = embed_exif_jpeg(vec![0xFF, 0xD8, 0xFF, 0xD9], &exif("Ansel")).unwrap();
+
+        assert_eq!(embedded[..4], [0xFF, 0xD8, 0xFF, 0xE1]);
+        assert_eq!(embedded[embedded.len() - 2..], [0xFF, 0xD9]);
+    }
+
+    #[test]
+    fn jpeg_metadata_larger_than_segment_is_rejected() {
+        let data = encode(&rgb(), ImageFormat::Jpeg);
+
+        assert!(embed_exif_jpeg(data.clone(), &vec![0; JPEG_EXIF_MAX_SIZE]).is_some());
+        assert!(embed_exif_jpeg(data, &vec![0; JPEG_EXIF_MAX_SIZE + 1]).is_none());
+    }
+
+    #[test]
+    fn oversized_jpeg_metadata_is_skipped() {
+        let dir = std::env::temp_dir().join(format!("pictura-encoder-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("large.jpg");
+
+        let data = encode(&rgb(), ImageFormat::Jpeg);
+        fs::write(&path, &data).unwrap();
+
+        embed_exif(&path, &rgb(), &vec![0; JPEG_EXIF_MAX_SIZE + 1]).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn png_chunk_is_inserted_after_ihdr() {
+        let embedded = embed_exif_png(encode(&rgb(), ImageFormat::Png), &exif("Ansel")).unwrap();
+
+        assert_eq!(&embedded[37..41], b"eXIf");
+        assert_eq!(artist(&embedded).as_deref(), Some("\"Ansel\""));
+        assert!(image::load_from_memory(&embedded).is_ok());
+    }
+
+    #[test]
+    fn simple_webp_is_converted_to_extended() {
+        let img = rgb();
+        let data = encode(&img, ImageFormat::WebP);
+        assert_eq!(&data[12..16], b"VP8L");
+
+        let embedded = embed_exif_webp(data, &img, &exif("Ansel")).unwrap();
+
+        assert_eq!(&embedded[12..16], b"VP8X");
+        assert_eq!(embedded[20], 0x08);
+        // canvas size minus one, 24 bits each
+        assert_eq!(embedded[24..30], [2, 0, 0, 1, 0, 0]);
+        assert_eq!(u32::from_le_bytes(embedded[4..8].try_into().unwrap()) as usize, embedded.len() - 8);
+        assert_eq!(artist(&embedded).as_deref(), Some("\"Ansel\""));
+        assert!(image::load_from_memory(&embedded).is_ok());
+    }
+
+    #[test]
+    fn extended_webp_keeps_its_flags() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(3, 2, Rgba([200, 100, 50, 128])));
+        let data = embed_exif_webp(encode(&img, ImageFormat::WebP), &img, &exif("Ansel")).unwrap();
+        assert_eq!(data[20], 0x18);
+
+        // embedding again keeps the existing VP8X header
+        let embedded = embed_exif_webp(data.clone(), &img, &exif("Ansel")).unwrap();
+
+        assert_eq!(embedded[8..30], data[8..30]);
+    }
+
+    #[test]
+    fn webp_chunks_are_padded_to_even_size() {
+        let img = rgb();
+        let data = encode(&img, ImageFormat::WebP);
+        let size = data.len();
+        let embedded = embed_exif_webp(data, &img, &[1, 2, 3]).unwrap();
+
+        // VP8X chunk, EXIF chunk header, data and padding
+        assert_eq!(embedded.len(), size + 18 + 8 + 3 + 1);
+        assert_eq!(embedded[embedded.len() - 4..], [1, 2, 3, 0]);
+        assert_eq!(u32::from_le_bytes(embedded[4..8].try_into().unwrap()) as usize, embedded.len() - 8);
+    }
+}
@@ -1,17 +1,33 @@
 use std::{fmt, fs};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::error::Error;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 
-use image::GenericImageView;
+use ignore::gitignore::GitignoreBuilder;
+use ignore::WalkBuilder;
+use image::{GenericImageView, ImageFormat};
 use log::{info, warn};
-use rand::Rng;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use walkdir::{DirEntry, WalkDir};
 
-use crate::common::{IMAGE_EXTENSIONS, paths};
-use crate::config::Config;
+use crate::color;
+use crate::color::Lab;
+use crate::common::{detect_format, is_image, paths, write_atomic};
+use crate::config::{Config, Derivatives, MetadataPolicy, OutputFormat, Source, SRCSET_PREFIX};
+use crate::duplicates;
+use crate::encoder;
 use crate::generator;
+use crate::metadata;
+use crate::migrate;
+use crate::metadata::Metadata;
+use crate::sidecar;
 
 /// Initialize a new gallery.
 pub fn init(config: &Config) -> Result<(), Box<dyn Error>> {
@@ -19,10 +35,9 @@ pub fn init(config: &Config) -> Result<(), Box<dyn Error>> {
     vec![
         paths::wallpapers(),
         paths::pictura(),
-        paths::compressed(),
-        paths::medium(),
     ]
         .into_iter()
+        .chain(config.sizes().keys().map(|size| paths::derivatives(size)))
         .try_for_each(|dir| -> io::Result<()> {
             if !dir.exists() {
                 fs::create_dir_all(dir)?;
@@ -31,19 +46,19 @@ pub fn init(config: &Config) -> Result<(), Box<dyn Error>> {
             Ok(())
         })?;
 
-    fs::write(
-        paths::config_file(),
+    write_atomic(
+        &paths::config_file(),
         toml::to_string(&config).unwrap(),
     )?;
 
-    fs::write(
-        paths::pictures_file(),
-        "",
+    write_atomic(
+        &paths::pictures_file(),
+        toml::to_string(&Pictures::default())?,
     )?;
 
-    fs::write(
-        paths::html_file(),
-        generator::gen_html(&config, Pictures::default())?,
+    write_atomic(
+        &paths::html_file(),
+        generator::gen_html(config, Pictures::default())?,
     )?;
 
     Ok(())
@@ -65,69 +80,70 @@ pub struct Picture {
     pub height: u32,
     /// Dominant color of an image in HEX format
     pub color: String,
-    /// Unique identifier of an image
-    pub id: u32,
-    /// Compressed path
-    pub compressed: Option<PathBuf>,
-    /// Medium path
-    pub medium: Option<PathBuf>,
+    /// BlurHash of an image, used as a placeholder while it loads
+    #[serde(default)]
+    pub blurhash: Option<String>,
+    /// Perceptual hash (dHash) of an image in HEX format, used to find near-duplicates
+    #[serde(default)]
+    pub phash: Option<String>,
+    /// Unique identifier of an image (BLAKE3 hash of the original file)
+    pub id: String,
+    /// Size of the original file in bytes
+    #[serde(default)]
+    pub size: u64,
+    /// Modification time of the original file in seconds since unix epoch
+    #[serde(default)]
+    pub modified: u64,
+    /// Fingerprint of sidecar files of an image, used to detect their changes
+    pub sidecar: Option<String>,
+    /// Tags set by user
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub tags: BTreeSet<String>,
     /// Original path
     pub original: Option<PathBuf>,
+    /// Derivative paths by size name
+    #[serde(default)]
+    pub derivatives: BTreeMap<String, PathBuf>,
+    /// Derivative paths in fallback format by size name
+    #[serde(default)]
+    pub fallbacks: BTreeMap<String, PathBuf>,
+    /// Most common colors of an image, ordered by weight
+    #[serde(default)]
+    pub palette: Vec<PaletteColor>,
+    /// Camera info and credits, not read yet for pictures added by older versions
+    pub metadata: Option<Metadata>,
 }
 
-impl Picture {
-    pub fn new(path: &Path, img: &image::DynamicImage) -> Result<Self, Box<dyn Error>> {
-        // image name (without extension)
-        let name = match path.file_stem() {
-            Some(name) => name.to_str().unwrap().to_string(),
-            None => return Err(io::Error::new(io::ErrorKind::Other, "Invalid path").into()),
-        };
-
-        // image extension
-        let extension = match path.extension() {
-            Some(extension) => {
-                let extension = extension.to_str().unwrap().to_string();
-                if !IMAGE_EXTENSIONS.contains(&extension.as_str()) {
-                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid extension").into());
-                }
-
-                extension
-            }
-            None => return Err(io::Error::new(io::ErrorKind::Other, "Invalid path").into()),
-        };
-
-        let category = match path.parent() {
-            Some(parent) => {
-                let parent = parent.file_name().unwrap().to_str().unwrap().to_string();
+/// Color of a picture palette.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PaletteColor {
+    /// Color in HEX format
+    pub color: String,
+    /// Share of image pixels closest to this color, from 0 to 1
+    pub weight: f64,
+}
 
-                if parent == paths::wallpapers().to_str().unwrap() {
-                    None
-                } else {
-                    Some(parent)
-                }
-            }
-            None => None,
-        };
+impl Picture {
+    pub fn new(path: &Path, img: &image::DynamicImage, id: String, palette_size: u8, sources: &[Source]) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let (name, extension, category) = parse_location(path, sources)?;
 
         let (width, height) = img.dimensions();
 
         // dominant color of an image in HEX format
-        let color_type = match img.color() {
-            image::ColorType::Rgb8 | image::ColorType::Rgb16 => color_thief::ColorFormat::Rgb,
-            image::ColorType::Rgba8 | image::ColorType::Rgba16 => color_thief::ColorFormat::Rgba,
-            _ => color_thief::ColorFormat::Rgb,
+        let primary = quantize(img, 5)?;
+        let color = rgb_to_hex(primary[0].r, primary[0].g, primary[0].b);
+
+        let palette = if palette_size == 5 {
+            primary
+        } else {
+            quantize(img, palette_size.max(2))?
         };
+        let palette = weigh_palette(img, &palette);
 
-        let color = color_thief::get_palette(
-            img.as_bytes(),
-            color_type,
-            10,
-            5,
-        )?[0];
-        let color = rgb_to_hex(color.r, color.g, color.b);
+        let blurhash = compute_blurhash(img)?;
+        let phash = duplicates::dhash(img);
 
-        // image id
-        let id = rand::thread_rng().gen_range(0..1000000);
+        let (size, modified) = file_stamp(path)?;
 
         let mapping = Self {
             name,
@@ -136,60 +152,275 @@ impl Picture {
             width,
             height,
             color,
+            blurhash: Some(blurhash),
+            phash: Some(format!("{:016x}", phash)),
             id,
-            compressed: None,
-            medium: None,
+            size,
+            modified,
+            sidecar: None,
+            tags: BTreeSet::new(),
             original: None,
+            derivatives: BTreeMap::new(),
+            fallbacks: BTreeMap::new(),
+            palette,
+            metadata: None,
         };
 
         Ok(mapping)
     }
 
-    pub fn setup_paths(&mut self) -> io::Result<()> {
+    /// Set up original path and derivative paths for the given sizes.
+    /// Returns previously used derivative paths which are no longer valid,
+    /// e.g. because the size was removed from config or its format has changed.
+    pub fn setup_paths(&mut self, sizes: &Derivatives, sources: &[Source]) -> io::Result<Vec<PathBuf>> {
         let gallery_root = get_pictura_root_dir()?;
 
-        if self.compressed.is_none() {
-            self.compressed = Some(gallery_root.join(paths::compressed()).join(self.to_string()));
-        }
+        let mut derivatives = BTreeMap::new();
+        let mut fallbacks = BTreeMap::new();
+
+        // derivatives named by an older scheme are kept, so they can be renamed instead of generated again
+        let keep = |old: Option<&PathBuf>, new: PathBuf| match old {
+            Some(old) if old.parent() == new.parent() && old.extension() == new.extension() && old.exists() => old.clone(),
+            _ => new,
+        };
 
-        if self.medium.is_none() {
-            self.medium = Some(gallery_root.join(paths::medium()).join(self.to_string()));
+        for (name, size) in sizes.iter().filter(|(name, _)| !self.is_redundant(name, sizes)) {
+            let dir = gallery_root.join(paths::derivatives(name));
+
+            derivatives.insert(
+                name.clone(),
+                keep(self.derivatives.get(name), dir.join(self.derivative_file_name(size.format))),
+            );
+
+            if let Some(fallback) = size.fallback() {
+                fallbacks.insert(
+                    name.clone(),
+                    keep(self.fallbacks.get(name), dir.join(self.derivative_file_name(Some(fallback)))),
+                );
+            }
         }
 
+        let old_derivatives = std::mem::replace(&mut self.derivatives, derivatives);
+        let old_fallbacks = std::mem::replace(&mut self.fallbacks, fallbacks);
+
+        let stale = old_derivatives
+            .into_values()
+            .chain(old_fallbacks.into_values())
+            .filter(|path| !self.derivatives.values().chain(self.fallbacks.values()).any(|p| p == path))
+            .collect();
+
         if self.original.is_none() {
-            self.original = match &self.category {
-                Some(category) => Some(gallery_root.join(paths::wallpapers()).join(category).join(format!("{}.{}", self.name, self.extension))),
-                None => Some(gallery_root.join(paths::wallpapers()).join(format!("{}.{}", self.name, self.extension))),
-            };
+            self.original = Some(self.resolve_original(sources, &gallery_root));
         }
 
+        Ok(stale)
+    }
+
+    /// Find path of the original by category, in the source directory whose prefix matches it.
+    fn resolve_original(&self, sources: &[Source], gallery_root: &Path) -> PathBuf {
+        let file_name = format!("{}.{}", self.name, self.extension);
+        let category = self.category.clone().unwrap_or_default();
+
+        let matched = sources
+            .iter()
+            .filter_map(|source| {
+                let relative = match &source.prefix {
+                    Some(prefix) if category == *prefix => "",
+                    Some(prefix) => category.strip_prefix(prefix.as_str())?.strip_prefix('/')?,
+                    None => category.as_str(),
+                };
+
+                Some((source, relative))
+            })
+            // the most specific prefix wins
+            .max_by_key(|(source, _)| source.prefix.as_ref().map_or(0, |prefix| prefix.len()));
+
+        match matched {
+            Some((source, relative)) => source.path.join(relative).join(file_name),
+            None => gallery_root.join(paths::wallpapers()).join(category).join(file_name),
+        }
+    }
+
+    /// Get file name of a derivative in the given format, same as the original if not set.
+    fn derivative_file_name(&self, format: Option<OutputFormat>) -> String {
+        match format {
+            Some(format) => format!("{}.{}", self, format.extension()),
+            None => format!("{}.{}", self, self.extension),
+        }
+    }
+}
+
+impl Picture {
+    /// Check if the id was assigned randomly by an older version of pictura.
+    pub fn has_legacy_id(&self) -> bool {
+        !is_content_id(&self.id)
+    }
+
+    /// Replace a legacy random id with the content hash of the original.
+    /// Derivatives keep their names until `rename_derivatives` is called.
+    /// Paths must be set up before calling this.
+    pub fn migrate_id(&mut self) -> io::Result<()> {
+        self.id = hash_file(&self.original.clone().unwrap())?;
+
         Ok(())
     }
+
+    /// Check if the original was changed since the picture was created.
+    /// Returns the new content hash if it was, otherwise refreshes the recorded size and mtime.
+    /// Paths must be set up before calling this.
+    pub fn check_modified(&mut self) -> io::Result<Option<String>> {
+        let original = self.original.clone().unwrap();
+        let (size, modified) = file_stamp(&original)?;
+
+        if size == self.size && modified == self.modified {
+            return Ok(None);
+        }
+
+        let id = hash_file(&original)?;
+        if id != self.id {
+            return Ok(Some(id));
+        }
+
+        // touched, but the content is the same
+        self.size = size;
+        self.modified = modified;
+
+        Ok(None)
+    }
+
+    /// Point the picture to a new original path (after it was moved or renamed).
+    /// Derivatives keep their names until `rename_derivatives` is called.
+    pub fn relocate(&mut self, path: &Path, sources: &[Source]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (name, extension, category) = parse_location(path, sources)?;
+
+        self.name = name;
+        self.extension = extension;
+        self.category = category;
+        self.original = Some(path.to_path_buf());
+
+        Ok(())
+    }
+
+    /// Read metadata again if sidecar files have changed or it was never read.
+    /// Returns true if metadata was read.
+    pub fn refresh_metadata(&mut self) -> bool {
+        let original = self.original.clone().unwrap();
+        if !original.exists() {
+            return false;
+        }
+
+        let fingerprint = match sidecar::read(&original) {
+            Ok(sidecar) => sidecar.map(|(_, fingerprint)| fingerprint),
+            Err(e) => {
+                warn!("Failed to read sidecar of {}\n{}", original.display(), e);
+                return false;
+            }
+        };
+
+        if self.metadata.is_some() && fingerprint == self.sidecar {
+            return false;
+        }
+
+        let (metadata, fingerprint) = read_metadata(&original, metadata::read_exif(&original).as_ref());
+        self.metadata = Some(metadata);
+        self.sidecar = fingerprint;
+
+        true
+    }
+
+    /// Get tags set by user together with tags from sidecar and embedded metadata.
+    pub fn all_tags(&self) -> BTreeSet<String> {
+        let metadata_tags = self.metadata.iter().flat_map(|metadata| metadata.tags.iter());
+
+        self.tags.iter().chain(metadata_tags).cloned().collect()
+    }
+
+    /// Check if any derivative of the given sizes has not been generated yet.
+    /// Paths must be set up before calling this.
+    pub fn lacks_derivatives(&self, sizes: &Derivatives) -> bool {
+        sizes
+            .iter()
+            .filter(|(name, _)| !self.is_redundant(name, sizes))
+            .any(|(name, size)| {
+                let exists = |paths: &BTreeMap<String, PathBuf>| paths.get(name).map(|p| p.exists()).unwrap_or(false);
+
+                !exists(&self.derivatives) || (size.fallback().is_some() && !exists(&self.fallbacks))
+            })
+    }
+
+    /// Check if a size is a srcset width not narrower than the picture, while a smaller one is too.
+    /// Derivatives are never upscaled, so it would be the same image as the one of the smaller width.
+    fn is_redundant(&self, name: &str, sizes: &Derivatives) -> bool {
+        let srcset_width = |name: &str| name.strip_prefix(SRCSET_PREFIX)?.parse::<u32>().ok();
+
+        let Some(width) = srcset_width(name) else {
+            return false;
+        };
+
+        width >= self.width
+            && sizes
+                .keys()
+                .filter_map(|name| srcset_width(name))
+                .any(|other| other >= self.width && other < width)
+    }
+
+    /// Check if the picture was created by an older version of pictura
+    /// and lacks metadata which requires decoding the image.
+    pub fn lacks_metadata(&self) -> bool {
+        self.blurhash.is_none() || self.phash.is_none() || self.palette.is_empty()
+    }
+
+    /// Check if any derivative is not named after the current name of the picture.
+    pub fn has_misnamed_derivatives(&self) -> bool {
+        let name = self.to_string();
+
+        self.derivatives
+            .values()
+            .chain(self.fallbacks.values())
+            .any(|path| path.file_stem().is_some_and(|stem| stem.to_string_lossy() != name))
+    }
+
+    /// Move already generated derivatives to paths matching the current name of the picture,
+    /// e.g. after it was relocated or its id was migrated.
+    pub fn rename_derivatives(&mut self) -> io::Result<()> {
+        let name = self.to_string();
+
+        self.derivatives
+            .values_mut()
+            .chain(self.fallbacks.values_mut())
+            .try_for_each(|path| -> io::Result<()> {
+                let extension = path.extension().unwrap_or_default().to_string_lossy();
+                let new = path.with_file_name(format!("{}.{}", name, extension));
+
+                if path.exists() && *path != new {
+                    fs::rename(&path, &new)?;
+                }
+
+                *path = new;
+
+                Ok(())
+            })
+    }
 }
 
 impl fmt::Display for Picture {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.category.is_none() {
-            write!(
-                f,
-                "w-{}_h-{}_c-{}_i-{}.{}",
-                self.width,
-                self.height,
-                self.color,
-                self.id,
-                self.extension
-            )
-        } else {
-            write!(
-                f,
-                "w-{}_h-{}_c-{}_i-{}_k-{}.{}",
-                self.width,
-                self.height,
-                self.color,
-                self.id,
-                self.category.clone().unwrap(),
-                self.extension
-            )
+        // name and extension tell apart byte-identical copies, which share the id
+        write!(
+            f,
+            "w-{}_h-{}_c-{}_i-{}_n-{}-{}",
+            self.width,
+            self.height,
+            self.color,
+            self.id,
+            self.name,
+            self.extension,
+        )?;
+
+        match &self.category {
+            // nested categories must not create directories
+            Some(category) => write!(f, "_k-{}", category.replace('/', "-")),
+            None => Ok(()),
         }
     }
 }
@@ -202,147 +433,948 @@ impl PartialEq for Picture {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Pictures {
+    /// Format version of the pictures file, see `migrate`
+    pub(crate) version: u32,
     pub(crate) pictures: Option<Vec<Picture>>,
 }
 
 impl Default for Pictures {
     fn default() -> Self {
-        Self { pictures: None }
+        Self {
+            version: migrate::CURRENT_VERSION,
+            pictures: None,
+        }
+    }
+}
+
+
+/// Settings for processing images, resolved from config once per sync.
+struct Processing {
+    /// Enabled wallpaper formats
+    formats: Vec<ImageFormat>,
+    /// Derivative sizes to generate
+    sizes: Derivatives,
+    /// Number of palette colors to extract
+    palette_size: u8,
+    /// What to do with EXIF metadata in derivatives
+    metadata: MetadataPolicy,
+    /// Source directories with resolved paths
+    sources: Vec<Source>,
+}
+
+impl Processing {
+    fn new(config: &Config, gallery_root: &Path) -> Self {
+        Self {
+            formats: config.image_formats(),
+            sizes: config.sizes(),
+            palette_size: config.palette_size,
+            metadata: config.metadata,
+            sources: config.sources(gallery_root),
+        }
+    }
+}
+
+/// Options for `sync`.
+#[derive(Debug, Default)]
+pub struct SyncOptions {
+    /// Number of images processed in parallel, 0 means number of CPUs
+    pub jobs: usize,
+    /// Files changed since the last sync. If set, other pictures are assumed to be up to date
+    /// and only new and removed files are looked for among them
+    pub changed: Option<HashSet<PathBuf>>,
+    /// Only plan the changes, without touching any files
+    pub dry_run: bool,
+}
+
+impl SyncOptions {
+    /// Check if a picture may have changed: its original or one of its sidecar files.
+    fn affects(&self, original: &Path) -> bool {
+        let changed = match &self.changed {
+            Some(changed) => changed,
+            None => return true,
+        };
+
+        changed.contains(original)
+            || changed.contains(&sidecar::sidecar_path(original))
+            || changed.contains(&sidecar::directory_sidecar_path(original))
+    }
+}
+
+/// Picture whose original was moved or renamed.
+#[derive(Serialize, Debug)]
+pub struct Move {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+/// Image which sync failed to read or process.
+#[derive(Serialize, Debug)]
+pub struct Failure {
+    pub path: PathBuf,
+    pub error: String,
+}
+
+/// Changes made by sync, or planned in a dry run. Pictures are listed by their originals.
+#[derive(Serialize, Debug, Default)]
+pub struct SyncSummary {
+    /// New pictures
+    pub added: Vec<PathBuf>,
+    /// Pictures whose derivatives are generated again or whose metadata is read again
+    pub updated: Vec<PathBuf>,
+    pub moved: Vec<Move>,
+    /// Pictures whose original is gone
+    pub removed: Vec<PathBuf>,
+    /// Pictures added by older versions whose ids or derivative names are migrated
+    pub migrated: Vec<PathBuf>,
+    /// Derivatives of sizes and formats which are no longer in config
+    pub stale: Vec<PathBuf>,
+    /// Images which failed and are retried by the next sync
+    pub failed: Vec<Failure>,
+}
+
+impl SyncSummary {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.updated.is_empty()
+            && self.moved.is_empty()
+            && self.removed.is_empty()
+            && self.migrated.is_empty()
+            && self.stale.is_empty()
+            && self.failed.is_empty()
     }
 }
 
+/// Changes to make by sync, planned without writing any files.
+/// Pictures already have their new locations, ids and metadata, but their derivatives are not renamed yet.
+struct SyncPlan {
+    /// All pictures of the gallery, including the ones to remove
+    pictures: Vec<Picture>,
+    /// Pictures whose derivatives have to be renamed
+    renamed: Vec<usize>,
+    /// Pictures with migrated ids or categories
+    migrated: Vec<usize>,
+    /// Pictures whose metadata was read again from changed sidecars
+    refreshed: Vec<usize>,
+    /// Pictures to process again with their new ids
+    update: Vec<(usize, String)>,
+    /// Moved pictures with their previous originals
+    moved: Vec<(usize, PathBuf)>,
+    /// New images with their ids
+    add: Vec<(PathBuf, String)>,
+    /// Pictures whose original is gone
+    remove: Vec<usize>,
+    /// Derivatives to delete
+    stale: Vec<PathBuf>,
+    /// Images which could not be read
+    failed: Vec<Failure>,
+}
+
+impl SyncPlan {
+    /// Get the planned changes. Takes failures out of the plan.
+    fn summary(&mut self) -> SyncSummary {
+        let original = |index: &usize| self.pictures[*index].original.clone().unwrap_or_default();
+
+        let mut updated: Vec<usize> = self.refreshed
+            .iter()
+            .chain(self.update.iter().map(|(index, _)| index))
+            .copied()
+            .collect();
+        updated.sort_unstable();
+        updated.dedup();
 
-/// Sync the gallery with the filesystem.
-pub fn sync() -> Result<(), Box<dyn Error>> {
+        SyncSummary {
+            added: self.add.iter().map(|(path, _)| path.clone()).collect(),
+            updated: updated.iter().map(original).collect(),
+            moved: self.moved
+                .iter()
+                .map(|(index, from)| Move { from: from.clone(), to: original(index) })
+                .collect(),
+            removed: self.remove.iter().map(original).collect(),
+            migrated: self.migrated.iter().map(original).collect(),
+            stale: self.stale.clone(),
+            failed: std::mem::take(&mut self.failed),
+        }
+    }
+}
+
+/// Sync the gallery with the filesystem, or only plan it in a dry run.
+pub fn sync(options: &SyncOptions) -> Result<SyncSummary, Box<dyn Error>> {
     let gallery_root = get_pictura_root_dir()?;
 
-    let pictures: Pictures = toml::from_str(
-        fs::read_to_string(gallery_root.join(paths::pictures_file()))?.as_str()
-    )?;
+    // held until the changes are written, a dry run only reads
+    let _lock = if options.dry_run { None } else { Some(GalleryLock::acquire(&gallery_root)?) };
 
-    let config: Config = toml::from_str(
-        fs::read_to_string(gallery_root.join(paths::config_file()))?.as_str()
-    )?;
+    let config = read_config(&gallery_root)?;
+    let processing = Processing::new(&config, &gallery_root);
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(options.jobs)
+        .build()?;
+
+    let mut plan = plan_sync(&gallery_root, &config, &processing, options, &pool)?;
 
-    let mut pictures = pictures.pictures.unwrap_or(Vec::new());
-    let mut to_add: Vec<PathBuf> = Vec::with_capacity(pictures.len());
-    let mut added: usize = 0;
-    let mut removed: usize = 0;
+    if options.dry_run {
+        return Ok(plan.summary());
+    }
+
+    apply_sync(&gallery_root, &config, &processing, &pool, plan)
+}
 
-    pictures
+/// Find out what sync has to do. Files are only read here, never written.
+fn plan_sync(
+    gallery_root: &Path,
+    config: &Config,
+    processing: &Processing,
+    options: &SyncOptions,
+    pool: &rayon::ThreadPool,
+) -> Result<SyncPlan, Box<dyn Error>> {
+    let sizes = &processing.sizes;
+    let sources = &processing.sources;
+
+    let mut pictures = read_pictures(gallery_root)?.pictures.unwrap_or_default();
+    let mut renamed: Vec<usize> = Vec::new();
+    let mut migrated: Vec<usize> = Vec::new();
+    let mut refreshed: Vec<usize> = Vec::new();
+    let mut stale: Vec<PathBuf> = Vec::new();
+
+    for mapping in pictures.iter_mut() {
+        stale.extend(
+            mapping
+                .setup_paths(sizes, sources)?
+                .into_iter()
+                .filter(|path| path.exists())
+        );
+    }
+
+    // byte-identical copies used to share derivatives, which are left to the copy they are named after,
+    // or to the first of them, the others get their own
+    let mut claimed: HashSet<PathBuf> = HashSet::new();
+    let (named, misnamed): (Vec<&mut Picture>, Vec<&mut Picture>) = pictures
         .iter_mut()
-        .try_for_each(|mapping| -> io::Result<()> {
-            mapping.setup_paths()?;
+        .partition(|mapping| !mapping.has_misnamed_derivatives());
 
-            Ok(())
-        })?;
+    for mapping in named.into_iter().chain(misnamed) {
+        let shared = mapping.derivatives
+            .values()
+            .chain(mapping.fallbacks.values())
+            .any(|path| !claimed.insert(path.clone()));
 
+        if shared {
+            mapping.derivatives.clear();
+            mapping.fallbacks.clear();
+            mapping.setup_paths(sizes, sources)?;
+        }
+    }
 
-    let images: Vec<PathBuf> = WalkDir::new(gallery_root.join(paths::wallpapers()))
-        .into_iter()
-        .filter_entry(|e| !is_hidden(e))
-        .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_file() && is_image(e.path()))
-        .map(|e| e.path().to_path_buf())
+    for (index, mapping) in pictures.iter_mut().enumerate() {
+        let original = gallery_root.join(mapping.original.clone().unwrap());
+        let mut migrate = false;
+
+        // categories used to be only the closest directory, so nested pictures need their derivatives renamed
+        if original.exists() && original.parent().and_then(|dir| category_of(dir, sources)) != mapping.category {
+            match mapping.relocate(&original, sources) {
+                Ok(()) => migrate = true,
+                Err(e) => warn!("Failed to update category of {}\n{}", original.display(), e),
+            }
+        }
+
+        // reading metadata does not require decoding, so there is no need to process the image again
+        let had_metadata = mapping.metadata.is_some();
+        let refresh = (options.affects(&original) || !had_metadata) && mapping.refresh_metadata();
+        if refresh && had_metadata {
+            refreshed.push(index);
+        }
+
+        // without the original there is nothing to hash, the picture will be removed
+        if mapping.has_legacy_id() && original.exists() {
+            match mapping.migrate_id() {
+                Ok(()) => migrate = true,
+                Err(e) => warn!("Failed to migrate id of {}\n{}", mapping.name, e),
+            }
+        }
+
+        // derivatives named before their names included the name of the picture
+        if migrate || mapping.has_misnamed_derivatives() {
+            migrated.push(index);
+            renamed.push(index);
+        }
+    }
+
+    let recorded: HashSet<PathBuf> = pictures
+        .iter()
+        .map(|picture| gallery_root.join(picture.original.clone().unwrap()))
         .collect();
 
-    images
+    let images = find_images(gallery_root, sources, &recorded, &config.exclude, config.follow_symlinks, &processing.formats)?;
+
+    let to_add: Vec<&PathBuf> = images
         .iter()
-        .for_each(|i| {
-            if pictures.iter().find(|m| &gallery_root.join(m.original.clone().unwrap()) == i).is_none() {
-                to_add.push(i.clone());
+        .filter(|i| !pictures.iter().any(|m| &gallery_root.join(m.original.clone().unwrap()) == *i))
+        .collect();
+
+    // pictures whose original was edited in place or which lack some derivatives
+    let checked: Vec<Result<(usize, String), Failure>> = pool.install(|| {
+        pictures
+            .par_iter_mut()
+            .enumerate()
+            .filter_map(|(index, picture)| {
+                let original = gallery_root.join(picture.original.clone().unwrap());
+                if !images.contains(&original) || !options.affects(&original) {
+                    return None;
+                }
+
+                match picture.check_modified() {
+                    Ok(Some(id)) => Some(Ok((index, id))),
+                    Ok(None) if picture.lacks_derivatives(sizes) || picture.lacks_metadata() => {
+                        Some(Ok((index, picture.id.clone())))
+                    }
+                    Ok(None) => None,
+                    Err(e) => Some(Err(Failure { path: original, error: format!("Failed to check image\n{e}") })),
+                }
+            })
+            .collect()
+    });
+
+    let mut failed: Vec<Failure> = Vec::new();
+    let mut update: Vec<(usize, String)> = Vec::with_capacity(checked.len());
+
+    for result in checked {
+        match result {
+            Ok(picture) => update.push(picture),
+            Err(failure) => failed.push(failure),
+        }
+    }
+
+    // pictures whose original is gone, they might have been moved or renamed
+    let mut missing: Vec<usize> = pictures
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| !images.contains(&gallery_root.join(m.original.clone().unwrap())))
+        .map(|(i, _)| i)
+        .collect();
+
+    let hashes: Vec<io::Result<String>> = pool.install(|| {
+        to_add
+            .par_iter()
+            .map(|image_path| hash_file(image_path))
+            .collect()
+    });
+
+    let mut moved: Vec<(usize, PathBuf)> = Vec::new();
+    let mut add: Vec<(PathBuf, String)> = Vec::with_capacity(to_add.len());
+
+    for (image_path, id) in to_add.into_iter().zip(hashes) {
+        let id = match id {
+            Ok(id) => id,
+            Err(e) => {
+                failed.push(Failure { path: image_path.clone(), error: format!("Failed to read image\n{e}") });
+                continue;
             }
-        });
+        };
 
-    for image_path in to_add.iter() {
-        // TODO: print warning
-        let img = image::open(image_path);
-        if img.is_err() {
-            warn!("Failed to open image: {}\n{}", image_path.display(), img.err().unwrap());
+        if let Some(position) = missing.iter().position(|&i| pictures[i].id == id) {
+            let index = missing.remove(position);
+            let from = pictures[index].original.clone().unwrap_or_default();
+
+            if let Err(e) = pictures[index].relocate(image_path, sources) {
+                warn!("Failed to move picture: {}\n{}", image_path.display(), e);
+                continue;
+            }
+
+            // sidecar files may differ at the new location
+            pictures[index].refresh_metadata();
+
+            if pictures[index].lacks_derivatives(sizes) || pictures[index].lacks_metadata() {
+                update.push((index, id));
+            }
+
+            moved.push((index, from));
+            renamed.push(index);
             continue;
         }
-        let img = img.unwrap();
 
-        let mapping = Picture::new(image_path, &img);
-        if mapping.is_err() {
-            warn!("Failed to create mapping: {}\n{}", image_path.display(), mapping.err().unwrap());
-            continue;
+        add.push((image_path.clone(), id));
+    }
+
+    Ok(SyncPlan {
+        pictures,
+        renamed,
+        migrated,
+        refreshed,
+        update,
+        moved,
+        add,
+        remove: missing,
+        stale,
+        failed,
+    })
+}
+
+/// Make the planned changes: rename, generate and delete derivatives, then save the gallery.
+/// Returns the changes which were made, images which failed to process are left out.
+fn apply_sync(
+    gallery_root: &Path,
+    config: &Config,
+    processing: &Processing,
+    pool: &rayon::ThreadPool,
+    mut plan: SyncPlan,
+) -> Result<SyncSummary, Box<dyn Error>> {
+    let mut summary = plan.summary();
+    let SyncPlan { mut pictures, renamed, refreshed, update, add, remove, stale, .. } = plan;
+
+    processing.sizes
+        .keys()
+        .map(|size| gallery_root.join(paths::derivatives(size)))
+        .try_for_each(fs::create_dir_all)?;
+
+    for path in &stale {
+        if let Err(e) = fs::remove_file(path) {
+            warn!("Failed to remove file: {}\n{}", path.display(), e);
         }
-        let mut mapping = mapping.unwrap();
+    }
+
+    for index in renamed {
+        if let Err(e) = pictures[index].rename_derivatives() {
+            warn!("Failed to rename derivatives of {}\n{}", pictures[index].name, e);
+        }
+    }
+
+    // images to process with indices of pictures they update, new ones have none
+    let tasks: Vec<(Option<usize>, PathBuf, String)> = update
+        .into_iter()
+        .map(|(index, id)| (Some(index), gallery_root.join(pictures[index].original.clone().unwrap()), id))
+        .chain(add.into_iter().map(|(image_path, id)| (None, image_path, id)))
+        .collect();
+
+    let chunk_size = match config.checkpoint_interval {
+        0 => tasks.len().max(1),
+        interval => interval,
+    };
+
+    let mut updated: Vec<usize> = refreshed;
+    let mut created: Vec<Picture> = Vec::new();
+
+    for (number, chunk) in tasks.chunks(chunk_size).enumerate() {
+        let processed: Vec<Result<Picture, String>> = pool.install(|| {
+            chunk
+                .par_iter()
+                .map(|(_, image_path, id)| try_process_image(image_path, id.clone(), processing))
+                .collect()
+        });
 
-        let (x, y) = (mapping.width, mapping.height);
-        let metadata_name = mapping.to_string();
+        for ((index, image_path, _), mapping) in chunk.iter().zip(processed) {
+            let mut mapping = match mapping {
+                Ok(mapping) => mapping,
+                Err(error) => {
+                    summary.failed.push(Failure { path: image_path.clone(), error });
+                    continue;
+                }
+            };
+
+            let Some(index) = *index else {
+                created.push(mapping);
+                continue;
+            };
 
-        // compressed
-        img
-            .thumbnail((x as f64 * 0.1).floor() as u32, (y as f64 * 0.1).floor() as u32)
-            .save(gallery_root.join(paths::compressed()).join(&metadata_name))?;
+            let is_new = |path: &PathBuf| mapping.derivatives.values().chain(mapping.fallbacks.values()).any(|p| p == path);
 
-        // medium
-        img
-            .thumbnail((x as f64 * 0.3).floor() as u32, (y as f64 * 0.3).floor() as u32)
-            .save(gallery_root.join(paths::medium()).join(&metadata_name))?;
+            pictures[index].derivatives.retain(|_, path| !is_new(path));
+            pictures[index].fallbacks.retain(|_, path| !is_new(path));
+            remove_derivatives(&pictures[index]);
 
-        mapping.setup_paths()?;
-        pictures.push(mapping);
+            // tags are set by user and cannot be read from the image
+            mapping.tags = std::mem::take(&mut pictures[index].tags);
+            pictures[index] = mapping;
 
-        added += 1;
+            updated.push(index);
+        }
+
+        // an interrupted sync resumes from here instead of processing these images again
+        if (number + 1) * chunk_size < tasks.len() {
+            write_checkpoint(gallery_root, &pictures, &created)?;
+        }
     }
 
-    let mappings = pictures
+    updated.sort_unstable();
+    updated.dedup();
+    summary.updated = updated
+        .iter()
+        .map(|&index| pictures[index].original.clone().unwrap_or_default())
+        .collect();
+
+    summary.added = created
+        .iter()
+        .map(|picture| picture.original.clone().unwrap_or_default())
+        .collect();
+
+    let added_ids: Vec<String> = created
+        .iter()
+        .map(|picture| picture.id.clone())
+        .collect();
+
+    let mappings: Vec<Picture> = pictures
         .into_iter()
-        .map(|m| {
-            if images.iter().find(|p| p == &&gallery_root.join(m.original.clone().unwrap())).is_none() {
-                let metadata_name = m.to_string();
-
-                vec![
-                    gallery_root.join(paths::compressed()).join(&metadata_name),
-                    gallery_root.join(paths::medium()).join(&metadata_name),
-                ]
-                    .into_iter()
-                    .for_each(|path| {
-                        if let Err(e) = fs::remove_file(&path) {
-                            warn!("Failed to remove file: {}\n{}", path.display(), e);
-                        }
-                    });
-
-                removed += 1;
+        .enumerate()
+        .filter_map(|(index, m)| {
+            if remove.contains(&index) {
+                remove_derivatives(&m);
                 None
             } else {
                 Some(m)
             }
         })
-        .filter_map(|m| m)
+        .chain(created)
         .collect();
 
-    let mappings = Pictures { pictures: Some(mappings) };
+    if config.warn_duplicates {
+        warn_duplicates(&mappings, &added_ids, config.duplicate_threshold);
+    }
+
+    write_pictures(gallery_root, config, mappings)?;
+
+    if !summary.migrated.is_empty() {
+        info!("{} images migrated from an older version", summary.migrated.len());
+    }
+
+    info!(
+        "{} images added, {} images updated, {} images moved, {} images removed",
+        summary.added.len(),
+        summary.updated.len(),
+        summary.moved.len(),
+        summary.removed.len(),
+    );
+
+    if !summary.failed.is_empty() {
+        let failures: Vec<String> = summary.failed
+            .iter()
+            .map(|failure| format!("{}: {}", failure.path.display(), failure.error.replace('\n', ": ")))
+            .collect();
+
+        warn!("{} images failed, the next sync retries them\n{}", failures.len(), failures.join("\n"));
+    }
+
+    Ok(summary)
+}
+
+/// Find pictures with colors perceptually closest to the given one.
+/// Returns up to `limit` pictures with their distances, closest first.
+pub fn find(color: &str, limit: usize) -> Result<Vec<(f64, Picture)>, Box<dyn Error>> {
+    let target = Lab::from_hex(color).ok_or(format!("Invalid color: {color}"))?;
+    let pictures = read_pictures(&get_pictura_root_dir()?)?;
+
+    let mut found: Vec<(f64, Picture)> = pictures.pictures
+        .unwrap_or_default()
+        .into_iter()
+        .map(|picture| (color::color_distance(&target, &color::palette_lab(&picture)), picture))
+        .filter(|(distance, _)| distance.is_finite())
+        .collect();
+
+    found.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+    found.truncate(limit);
+
+    Ok(found)
+}
+
+/// Find groups of pictures which look the same.
+/// Each group is ordered from the highest resolution copy to the lowest.
+/// Threshold defaults to the one from config.
+pub fn find_duplicates(threshold: Option<u32>) -> Result<Vec<Vec<Picture>>, Box<dyn Error>> {
+    let gallery_root = get_pictura_root_dir()?;
+    let config = read_config(&gallery_root)?;
+    let pictures = read_pictures(&gallery_root)?.pictures.unwrap_or_default();
+
+    let unhashed = pictures.iter().filter(|picture| picture.phash.is_none()).count();
+    if unhashed > 0 {
+        warn!("{unhashed} images have no perceptual hash yet, run sync to compute them");
+    }
+
+    let groups = duplicates::find_groups(&pictures, threshold.unwrap_or(config.duplicate_threshold));
+
+    let mut pictures: Vec<Option<Picture>> = pictures.into_iter().map(Some).collect();
+
+    Ok(groups
+        .into_iter()
+        .map(|group| group.into_iter().filter_map(|index| pictures[index].take()).collect())
+        .collect())
+}
+
+/// Remove originals of all but the highest resolution copy in each group of duplicates,
+/// then sync the gallery to drop their derivatives.
+pub fn keep_highest(groups: &[Vec<Picture>]) -> Result<(), Box<dyn Error>> {
+    let gallery_root = get_pictura_root_dir()?;
+    let _lock = GalleryLock::acquire(&gallery_root)?;
+
+    for picture in groups.iter().flat_map(|group| group.iter().skip(1)) {
+        let original = gallery_root.join(picture.original.clone().unwrap());
+
+        match fs::remove_file(&original) {
+            Ok(()) => info!("Removed {}", original.display()),
+            Err(e) => warn!("Failed to remove file: {}\n{}", original.display(), e),
+        }
+    }
+
+    sync(&SyncOptions::default())?;
+
+    Ok(())
+}
+
+/// Warn about added pictures which look the same as other pictures of the gallery.
+fn warn_duplicates(pictures: &[Picture], added_ids: &[String], threshold: u32) {
+    for (index, picture) in pictures.iter().enumerate() {
+        if !added_ids.contains(&picture.id) {
+            continue;
+        }
+
+        // compare added pictures between themselves only once
+        let duplicate = pictures
+            .iter()
+            .enumerate()
+            .filter(|(other, p)| *other < index || !added_ids.contains(&p.id))
+            .find(|(other, p)| *other != index && duplicates::is_duplicate(picture, p, threshold));
+
+        if let Some((_, duplicate)) = duplicate {
+            warn!(
+                "{} looks like a duplicate of {}",
+                picture.original.clone().unwrap_or_default().display(),
+                duplicate.original.clone().unwrap_or_default().display(),
+            );
+        }
+    }
+}
+
+/// Whether this process holds the gallery lock, so that nested writes don't try to lock it again.
+static LOCKED: AtomicBool = AtomicBool::new(false);
+
+/// Advisory lock of a gallery, held while it is written so that concurrent runs don't overwrite each other.
+/// Released when dropped, also when the process is killed.
+pub struct GalleryLock {
+    /// Locked file, not set for nested locks
+    file: Option<File>,
+}
+
+impl GalleryLock {
+    /// Lock the gallery. Fails right away if another process holds the lock.
+    pub fn acquire(gallery_root: &Path) -> Result<Self, Box<dyn Error>> {
+        if LOCKED.load(Ordering::SeqCst) {
+            return Ok(Self { file: None });
+        }
+
+        let path = gallery_root.join(paths::lock_file());
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
 
-    fs::write(
-        gallery_root.join(paths::pictures_file()),
-        toml::to_string(&mappings)?,
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let holder = fs::read_to_string(&path).unwrap_or_default();
+                let holder = match holder.trim() {
+                    "" => String::new(),
+                    pid => format!(" (process {pid})"),
+                };
+
+                return Err(format!("Gallery is busy, another pictura{holder} is writing it. Try again once it finishes").into());
+            }
+            Err(TryLockError::Error(e)) => return Err(format!("Failed to lock {}\n{}", path.display(), e).into()),
+        }
+
+        // only informative, the lock itself is held by the open file
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+
+        LOCKED.store(true, Ordering::SeqCst);
+
+        Ok(Self { file: Some(file) })
+    }
+}
+
+impl Drop for GalleryLock {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            LOCKED.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Save pictures processed so far without generating the page.
+fn write_checkpoint(gallery_root: &Path, pictures: &[Picture], created: &[Picture]) -> Result<(), Box<dyn Error>> {
+    /// Same layout as `Pictures`, without moving the pictures
+    #[derive(Serialize)]
+    struct Checkpoint<'a> {
+        version: u32,
+        pictures: Vec<&'a Picture>,
+    }
+
+    let checkpoint = Checkpoint {
+        version: migrate::CURRENT_VERSION,
+        pictures: pictures.iter().chain(created).collect(),
+    };
+
+    write_atomic(
+        &gallery_root.join(paths::pictures_file()),
+        toml::to_string(&checkpoint)?,
     )?;
 
+    Ok(())
+}
+
+/// Save pictures and generate the gallery page.
+pub fn write_pictures(gallery_root: &Path, config: &Config, pictures: Vec<Picture>) -> Result<(), Box<dyn Error>> {
+    let pictures = Pictures { pictures: Some(pictures), ..Default::default() };
 
-    fs::write(
-        gallery_root.join(paths::html_file()),
-        generator::gen_html(&config, mappings)?,
+    write_atomic(
+        &gallery_root.join(paths::pictures_file()),
+        toml::to_string(&pictures)?,
     )?;
 
-    info!("{added} images added, {removed} images removed");
+    write_atomic(
+        &gallery_root.join(paths::html_file()),
+        generator::gen_html(config, pictures)?,
+    )?;
 
     Ok(())
 }
 
-/// Check if a file is hidden.
-fn is_hidden(entry: &DirEntry) -> bool {
-    entry.file_name()
-        .to_str()
-        .map(|s| s.starts_with("."))
-        .unwrap_or(false)
+/// Read pictures, upgrading files written by older versions.
+pub fn read_pictures(gallery_root: &Path) -> Result<Pictures, Box<dyn Error>> {
+    migrate::parse_pictures(
+        fs::read_to_string(gallery_root.join(paths::pictures_file()))?.as_str()
+    )
+}
+
+/// Read config, upgrading files written by older versions.
+pub fn read_config(gallery_root: &Path) -> Result<Config, Box<dyn Error>> {
+    let config = migrate::parse_config(
+        fs::read_to_string(gallery_root.join(paths::config_file()))?.as_str()
+    )?;
+
+    config.validate()?;
+
+    Ok(config)
+}
+
+/// Process an image, turning panics of decoders on malformed files into errors,
+/// so that a single broken image does not stop the sync.
+fn try_process_image(image_path: &Path, id: String, processing: &Processing) -> Result<Picture, String> {
+    panic::catch_unwind(AssertUnwindSafe(|| process_image(image_path, id, processing)))
+        .unwrap_or_else(|panic| {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+
+            Err(format!("Failed to process image\n{message}"))
+        })
+}
+
+/// Decode an image, gather its metadata and generate derivatives.
+fn process_image(image_path: &Path, id: String, processing: &Processing) -> Result<Picture, String> {
+    let img = open_image(image_path, &processing.formats)
+        .map_err(|e| format!("Failed to open image\n{e}"))?;
+
+    let exif = metadata::read_exif(image_path);
+
+    // dimensions and derivatives are of the upright image
+    let img = match &exif {
+        Some(exif) => metadata::apply_orientation(img, metadata::orientation(exif)),
+        None => img,
+    };
+
+    let (info, sidecar) = read_metadata(image_path, exif.as_ref());
+
+    let exif = match exif.map(|exif| metadata::derivative_exif(&exif, processing.metadata)) {
+        Some(Ok(exif)) => exif,
+        Some(Err(e)) => {
+            warn!("Failed to copy metadata: {}\n{}", image_path.display(), e);
+            None
+        }
+        None => None,
+    };
+
+    let mut mapping = Picture::new(image_path, &img, id, processing.palette_size, &processing.sources)
+        .map_err(|e| format!("Failed to create mapping\n{e}"))?;
+
+    mapping.metadata = Some(info);
+    mapping.sidecar = sidecar;
+
+    mapping
+        .setup_paths(&processing.sizes, &processing.sources)
+        .map_err(|e| format!("Failed to setup paths\n{e}"))?;
+
+    generate_derivatives(&mapping, &img, &processing.sizes, exif.as_deref())
+        .map_err(|e| format!("Failed to generate derivatives\n{e}"))?;
+
+    Ok(mapping)
+}
+
+/// Read metadata of an image from the file itself and from its sidecar files, which take precedence.
+/// Returns the metadata and a fingerprint of the sidecar files.
+fn read_metadata(path: &Path, exif: Option<&exif::Exif>) -> (Metadata, Option<String>) {
+    let embedded = Metadata::read(path, exif);
+
+    match sidecar::read(path) {
+        Ok(Some((sidecar, fingerprint))) => (sidecar.or(embedded), Some(fingerprint)),
+        Ok(None) => (embedded, None),
+        Err(e) => {
+            warn!("Failed to read sidecar of {}\n{}", path.display(), e);
+            (embedded, None)
+        }
+    }
+}
+
+/// Decode an image using the format detected by its contents rather than by its extension.
+fn open_image(path: &Path, formats: &[ImageFormat]) -> Result<image::DynamicImage, Box<dyn Error + Send + Sync>> {
+    let format = match detect_format(path)? {
+        Some(format) if formats.contains(&format) => format,
+        Some(format) => return Err(format!("{:?} format is not enabled", format).into()),
+        None => return Err("Unknown image format".into()),
+    };
+
+    let mut reader = image::io::Reader::open(path)?;
+    reader.set_format(format);
+
+    Ok(reader.decode()?)
+}
+
+/// Generate derivatives of all sizes for a picture, with EXIF metadata if given.
+/// Paths must be set up before calling this.
+fn generate_derivatives(picture: &Picture, img: &image::DynamicImage, sizes: &Derivatives, exif: Option<&[u8]>) -> image::ImageResult<()> {
+    sizes
+        .iter()
+        .filter(|(name, _)| picture.derivatives.contains_key(*name))
+        .try_for_each(|(name, size)| {
+            let (width, height) = size.bounds(picture.width, picture.height);
+            let thumbnail = img.thumbnail(width, height);
+
+            let path = &picture.derivatives[name];
+            encoder::save(&thumbnail, path, size.format, size.quality)?;
+            if let Some(exif) = exif {
+                encoder::embed_exif(path, &thumbnail, exif)?;
+            }
+
+            if let Some(fallback) = size.fallback() {
+                let path = &picture.fallbacks[name];
+                encoder::save(&thumbnail, path, Some(fallback), size.quality)?;
+                if let Some(exif) = exif {
+                    encoder::embed_exif(path, &thumbnail, exif)?;
+                }
+            }
+
+            Ok(())
+        })
+}
+
+/// Remove all derivatives of a picture, including fallbacks.
+fn remove_derivatives(picture: &Picture) {
+    picture.derivatives
+        .values()
+        .chain(picture.fallbacks.values())
+        .filter(|path| path.exists())
+        .for_each(|path| {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("Failed to remove file: {}\n{}", path.display(), e);
+            }
+        });
+}
+
+/// Find images in the source directories, sorted by path within each one.
+/// Hidden files, files matched by `.picturaignore` files
+/// (in the gallery root or any directory inside) and by the exclude patterns are skipped.
+/// Symlink loops are reported and skipped, and an image reachable by several paths is only taken once,
+/// by the path it was added by or else by a path without symlinks.
+fn find_images(gallery_root: &Path, sources: &[Source], recorded: &HashSet<PathBuf>, exclude: &[String], follow_symlinks: bool, formats: &[ImageFormat]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut images: Vec<(PathBuf, PathBuf, bool)> = Vec::new();
+    let root_ignore = gallery_root.join(paths::ignore_file());
+
+    for source in sources {
+        if !source.path.is_dir() {
+            warn!("Source directory does not exist: {}", source.path.display());
+            continue;
+        }
+
+        let mut builder = GitignoreBuilder::new(&source.path);
+        for pattern in exclude {
+            builder.add_line(None, pattern)?;
+        }
+        let exclude = builder.build()?;
+
+        let mut walker = WalkBuilder::new(&source.path);
+
+        // sources may be outside the gallery, so its ignore file is not found by walking up from them
+        if root_ignore.is_file() {
+            if let Some(e) = walker.add_ignore(&root_ignore) {
+                warn!("Failed to read {}\n{}", root_ignore.display(), e);
+            }
+        }
+
+        let found = walker
+            .standard_filters(false)
+            .hidden(true)
+            .parents(false)
+            .add_custom_ignore_filename(paths::ignore_file())
+            // loops are detected by the walker and reported as errors
+            .follow_links(follow_symlinks)
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .filter_entry(move |entry| {
+                let is_dir = entry.file_type().is_some_and(|file_type| file_type.is_dir());
+
+                !exclude.matched_path_or_any_parents(entry.path(), is_dir).is_ignore()
+            })
+            .build()
+            .filter_map(|entry| match entry {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    warn!("Failed to read source directory: {}\n{}", source.path.display(), e);
+                    None
+                }
+            })
+            .filter(|e| e.file_type().is_some_and(|file_type| file_type.is_file()) && is_image(e.path(), formats))
+            .map(|e| e.path().to_path_buf());
+
+        let root = fs::canonicalize(&source.path).unwrap_or_else(|_| source.path.clone());
+
+        for image in found {
+            let (real, aliased) = if follow_symlinks {
+                let real = fs::canonicalize(&image).unwrap_or_else(|_| image.clone());
+                let direct = image.strip_prefix(&source.path).map(|relative| root.join(relative));
+                let aliased = direct.map_or(true, |direct| direct != real);
+
+                (real, aliased)
+            } else {
+                (image.clone(), false)
+            };
+
+            images.push((image, real, aliased));
+        }
+    }
+
+    // an image reachable by several paths keeps the one it was added by, or else the one without symlinks
+    let rank = |(image, _, aliased): &(PathBuf, PathBuf, bool)| (!recorded.contains(image), *aliased);
+
+    let mut chosen: HashMap<&PathBuf, usize> = HashMap::new();
+    for (index, found) in images.iter().enumerate() {
+        chosen
+            .entry(&found.1)
+            .and_modify(|best| if rank(found) < rank(&images[*best]) { *best = index })
+            .or_insert(index);
+    }
+
+    let chosen: HashSet<usize> = chosen.into_values().collect();
+
+    Ok(images
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| chosen.contains(index))
+        .map(|(_, (image, _, _))| image.clone())
+        .collect())
 }
 
 pub fn get_pictura_root_dir() -> io::Result<PathBuf> {
-    let pwd = PathBuf::from(std::env::current_dir()?);
+    let pwd = std::env::current_dir()?;
 
     for ancestor in pwd.ancestors() {
         match is_pictura_root(ancestor.to_path_buf()) {
@@ -372,11 +1404,209 @@ fn is_pictura_root(p: PathBuf) -> io::Result<bool> {
     )
 }
 
+/// Get category of images in a directory: prefix of its source directory followed by
+/// its path relative to the source directory, with components separated by `/`, e.g. "Nature/Forest".
+/// Directories outside of the source directories fall back to their own name.
+fn category_of(dir: &Path, sources: &[Source]) -> Option<String> {
+    let matched = sources
+        .iter()
+        .filter_map(|source| Some((source, dir.strip_prefix(&source.path).ok()?)))
+        // nested source directories take precedence over their parents
+        .max_by_key(|(source, _)| source.path.components().count());
+
+    let (source, relative) = match matched {
+        Some(matched) => matched,
+        None => return dir.file_name().map(|name| name.to_string_lossy().to_string()),
+    };
+
+    let components: Vec<String> = source.prefix
+        .iter()
+        .cloned()
+        .chain(relative.components().map(|component| component.as_os_str().to_string_lossy().to_string()))
+        .collect();
+
+    if components.is_empty() {
+        None
+    } else {
+        Some(components.join("/"))
+    }
+}
+
+/// Get image name (without extension), extension and category from its path.
+fn parse_location(path: &Path, sources: &[Source]) -> Result<(String, String, Option<String>), Box<dyn Error + Send + Sync>> {
+    // image name (without extension)
+    let name = match path.file_stem() {
+        Some(name) => name.to_str().unwrap().to_string(),
+        None => return Err(io::Error::other("Invalid path").into()),
+    };
+
+    // image extension
+    let extension = match path.extension() {
+        Some(extension) => {
+            let extension = extension.to_str().unwrap().to_string();
+            if ImageFormat::from_extension(&extension).is_none() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid extension").into());
+            }
+
+            extension
+        }
+        None => return Err(io::Error::other("Invalid path").into()),
+    };
+
+    let category = path.parent().and_then(|dir| category_of(dir, sources));
+
+    Ok((name, extension, category))
+}
+
+/// Get size in bytes and modification time in seconds since unix epoch of a file.
+fn file_stamp(path: &Path) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    Ok((metadata.len(), modified))
+}
+
+/// Compute BLAKE3 hash of a file contents in HEX format.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(fs::File::open(path)?)?;
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Check if id is a content hash produced by `hash_file`.
+fn is_content_id(id: &str) -> bool {
+    id.len() == blake3::OUT_LEN * 2 && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Get most common colors of an image.
+fn quantize(img: &image::DynamicImage, max_colors: u8) -> Result<Vec<color_thief::Color>, color_thief::Error> {
+    match img {
+        image::DynamicImage::ImageRgb8(rgb) => color_thief::get_palette(
+            rgb.as_raw(),
+            color_thief::ColorFormat::Rgb,
+            10,
+            max_colors,
+        ),
+        image::DynamicImage::ImageRgba8(rgba) => color_thief::get_palette(
+            rgba.as_raw(),
+            color_thief::ColorFormat::Rgba,
+            10,
+            max_colors,
+        ),
+        // grayscale, 16-bit and float images
+        _ => color_thief::get_palette(
+            img.to_rgba8().as_raw(),
+            color_thief::ColorFormat::Rgba,
+            10,
+            max_colors,
+        ),
+    }
+}
+
+/// Weigh palette colors by share of pixels closest to each of them.
+/// Returns colors ordered by weight, heaviest first.
+fn weigh_palette(img: &image::DynamicImage, palette: &[color_thief::Color]) -> Vec<PaletteColor> {
+    let sample = img.thumbnail(128, 128).to_rgba8();
+    let mut counts = vec![0usize; palette.len()];
+
+    sample
+        .pixels()
+        // color thief ignores mostly transparent pixels as well
+        .filter(|pixel| pixel[3] >= 125)
+        .for_each(|pixel| {
+            let distance = |color: &color_thief::Color| {
+                [(color.r, pixel[0]), (color.g, pixel[1]), (color.b, pixel[2])]
+                    .iter()
+                    .map(|(a, b)| (*a as i32 - *b as i32).pow(2))
+                    .sum::<i32>()
+            };
+
+            let closest = palette
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, color)| distance(color))
+                .map(|(i, _)| i);
+
+            if let Some(closest) = closest {
+                counts[closest] += 1;
+            }
+        });
+
+    let total = counts.iter().sum::<usize>().max(1) as f64;
+
+    let mut palette: Vec<PaletteColor> = palette
+        .iter()
+        .zip(counts)
+        .map(|(color, count)| PaletteColor {
+            color: rgb_to_hex(color.r, color.g, color.b),
+            weight: (count as f64 / total * 10000.0).round() / 10000.0,
+        })
+        .collect();
+
+    palette.sort_by(|a, b| b.weight.total_cmp(&a.weight));
+
+    palette
+}
+
+/// Compute BlurHash of an image.
+/// A tiny thumbnail is enough, since the hash only keeps a few low frequency components.
+fn compute_blurhash(img: &image::DynamicImage) -> Result<String, blurhash::Error> {
+    let sample = img.thumbnail(64, 64).to_rgba8();
+
+    // 4 components along the longer side, 3 along the shorter one
+    let (x, y) = if sample.width() >= sample.height() { (4, 3) } else { (3, 4) };
+
+    blurhash::encode(x, y, sample.width(), sample.height(), sample.as_raw())
+}
+
 fn rgb_to_hex(r: u8, g: u8, b: u8) -> String {
     format!("{:02x}{:02x}{:02x}", r, g, b)
 }
 
-fn is_image(path: &Path) -> bool {
-    let ext = path.extension().unwrap_or("".as_ref()).to_str().unwrap();
-    IMAGE_EXTENSIONS.contains(&ext)
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn picture(name: &str, extension: &str, derivative: &str) -> Picture {
+        toml::from_str(&format!(
+            r#"
+            name = "{name}"
+            extension = "{extension}"
+            category = "Nature/Forest"
+            width = 64
+            height = 48
+            color = "18172c"
+            id = "1fad991b5d5ea5bfdb3e1b3b7d7655f926749cb06669bae1feaa7c46d7327157"
+            [derivatives]
+            medium = "{derivative}"
+            "#
+        )).unwrap()
+    }
+
+    #[test]
+    fn identical_copies_get_own_derivatives() {
+        let a = picture("a", "png", "");
+        let b = picture("b", "png", "");
+        let jpeg = picture("a", "jpeg", "");
+        let jpg = picture("a", "jpg", "");
+
+        assert_ne!(a.derivative_file_name(Some(OutputFormat::Webp)), b.derivative_file_name(Some(OutputFormat::Webp)));
+        assert_ne!(jpeg.derivative_file_name(Some(OutputFormat::Webp)), jpg.derivative_file_name(Some(OutputFormat::Webp)));
+        assert!(!a.to_string().contains('/'));
+    }
+
+    #[test]
+    fn derivatives_named_by_older_scheme_are_misnamed() {
+        let old = ".pictura/medium/w-64_h-48_c-18172c_i-1fad991b5d5ea5bfdb3e1b3b7d7655f926749cb06669bae1feaa7c46d7327157_k-Nature-Forest.webp";
+        assert!(picture("a", "png", old).has_misnamed_derivatives());
+
+        let mut current = picture("a", "png", "");
+        current.derivatives.insert("medium".to_string(), PathBuf::from(format!(".pictura/medium/{current}.webp")));
+        assert!(!current.has_misnamed_derivatives());
+    }
+}
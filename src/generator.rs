@@ -1,17 +1,28 @@
-use std::collections::HashSet;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::fs;
 use std::fs::read_to_string;
 use std::path::PathBuf;
 
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
 use handlebars::{Handlebars, handlebars_helper, RenderError};
+use image::{ColorType, ImageEncoder};
+use image::codecs::png::PngEncoder;
+use log::warn;
 use pathdiff::diff_paths;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
-use crate::common::{capitalize, paths};
-use crate::config::Config;
+use crate::color;
+use crate::common::{capitalize, paths, write_atomic};
+use crate::config::{Config, SortKey, SRCSET_PREFIX};
 use crate::gallery;
 use crate::gallery::get_pictura_root_dir;
 
+/// Rendered width of a card image for srcset, matches grid columns of the default template.
+const SRCSET_SIZES: &str = "(min-width: 1400px) 17vw, (min-width: 992px) 25vw, (min-width: 768px) 33vw, (min-width: 576px) 50vw, 100vw";
+
 handlebars_helper!(join_path: |path: Value, {with:str="."}| {
     let path = PathBuf::from(path.as_str().unwrap_or(""));
     let with_path = PathBuf::from(with);
@@ -20,13 +31,54 @@ handlebars_helper!(join_path: |path: Value, {with:str="."}| {
 });
 
 handlebars_helper!(relative_path: |path: Value| {
-    let path = PathBuf::from(path.as_str().unwrap());
-    let page = fs::canonicalize(paths::html_file()).unwrap();
+    path.as_str().map(relative_to_page).unwrap_or_default()
+});
 
-    diff_paths(
-        fs::canonicalize(path).unwrap(),
-        page.clone().parent().unwrap(),
-    ).unwrap().to_str().unwrap().to_string()
+handlebars_helper!(srcset: |picture: Value, **kwargs| {
+    let remote = kwargs.get("remote").and_then(|remote| remote.as_str());
+
+    srcset_entries(&picture)
+        .into_iter()
+        .map(|(width, path)| {
+            let path = relative_to_page(path);
+            let url = match remote {
+                Some(remote) => PathBuf::from(remote).join(path).to_str().unwrap().to_string(),
+                None => path,
+            };
+
+            format!("{url} {width}w")
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+});
+
+handlebars_helper!(srcset_type: |picture: Value| {
+    srcset_entries(&picture)
+        .first()
+        .and_then(|(_, path)| image::ImageFormat::from_path(path).ok())
+        .map(|format| format.to_mime_type())
+        .unwrap_or("")
+});
+
+handlebars_helper!(mime_type: |path: Value| {
+    image::ImageFormat::from_path(path.as_str().unwrap_or(""))
+        .map(|format| format.to_mime_type())
+        .unwrap_or("application/octet-stream")
+});
+
+handlebars_helper!(blurhash_url: |picture: Value| {
+    match picture["blurhash"].as_str() {
+        Some(hash) => blurhash_data_url(
+            hash,
+            picture["width"].as_u64().unwrap_or(1) as u32,
+            picture["height"].as_u64().unwrap_or(1) as u32,
+        ).unwrap_or_default(),
+        None => String::new(),
+    }
+});
+
+handlebars_helper!(repeat: |string: str, times: u64| {
+    string.repeat(times as usize)
 });
 
 handlebars_helper!(title_case: |string: Value| {
@@ -67,7 +119,7 @@ pub fn gen_html(config: &Config, pictures: gallery::Pictures) -> Result<String,
     let theme = {
         let default_theme = include_str!("gallery.hbs");
         if let Ok(dir) = get_pictura_root_dir() {
-            let custom_theme = dir.join(paths::pictura()).join("index.hbs");
+            let custom_theme = dir.join(paths::template_file());
             if custom_theme.exists() {
                 read_to_string(custom_theme).unwrap_or(default_theme.to_string())
             } else {
@@ -85,21 +137,68 @@ pub fn gen_html(config: &Config, pictures: gallery::Pictures) -> Result<String,
     reg.register_helper("contrast-color", Box::new(contrast_color));
     reg.register_helper("is-dark", Box::new(is_dark_helper));
     reg.register_helper("join-path", Box::new(join_path));
+    reg.register_helper("mime-type", Box::new(mime_type));
+    reg.register_helper("srcset", Box::new(srcset));
+    reg.register_helper("srcset-type", Box::new(srcset_type));
+    reg.register_helper("blurhash-url", Box::new(blurhash_url));
+    reg.register_helper("repeat", Box::new(repeat));
 
-    let mut pictures = pictures.pictures.unwrap_or(Vec::new());
+    let pictures = pictures.pictures.unwrap_or_default();
     let mut categories: HashSet<String> = HashSet::new();
     let mut extensions: HashSet<String> = HashSet::new();
+    let mut tags: BTreeSet<String> = BTreeSet::new();
+    let mut categories_count: BTreeMap<String, usize> = BTreeMap::new();
 
     pictures
         .iter()
         .for_each(|mapping| {
             if let Some(category) = &mapping.category {
                 categories.insert(category.clone());
+                *categories_count.entry(category.clone()).or_default() += 1;
             }
             extensions.insert(mapping.extension.clone());
+            tags.extend(mapping.all_tags());
         });
 
-    pictures.sort_unstable_by_key(|mapping| mapping.name.clone());
+    let pictures = sort_pictures(pictures, config.sort_by, config.sort_descending);
+
+    let similar = similar_pictures(&pictures, config.similar_count);
+    let pictures: Vec<Value> = pictures
+        .iter()
+        .zip(similar)
+        .map(|(picture, similar)| -> Result<Value, RenderError> {
+            let mut value = serde_json::to_value(picture)?;
+            value["tags"] = serde_json::to_value(picture.all_tags())?;
+
+            let (preview, preview_fallback) = preview(picture);
+            value["preview"] = serde_json::to_value(preview)?;
+            value["preview_fallback"] = serde_json::to_value(preview_fallback)?;
+
+            // metadata comes from the images, so only web links are rendered as links
+            let source_url = picture.metadata
+                .as_ref()
+                .and_then(|metadata| metadata.source.as_deref())
+                .filter(|source| is_web_url(source));
+            value["source_url"] = serde_json::to_value(source_url)?;
+
+            value["similar"] = similar
+                .into_iter()
+                .map(|(index, distance)| {
+                    let other = &pictures[index];
+
+                    json!({
+                        "id": other.id,
+                        "name": other.name,
+                        "original": other.original,
+                        "derivatives": other.derivatives,
+                        "distance": distance,
+                    })
+                })
+                .collect();
+
+            Ok(value)
+        })
+        .collect::<Result<_, _>>()?;
 
     reg.render(
         "gallery",
@@ -107,13 +206,244 @@ pub fn gen_html(config: &Config, pictures: gallery::Pictures) -> Result<String,
             "config": config,
             "pictures": pictures,
             "categories": categories,
+            "category_tree": category_tree(&categories_count),
             "extensions": extensions,
+            "tags": tags,
+            "sizes": SRCSET_SIZES,
         }))
 }
 
+/// Similar pictures saved with the palettes they were found for.
+#[derive(Serialize, Deserialize)]
+struct SimilarCache {
+    fingerprint: String,
+    similar: Vec<Vec<(usize, f64)>>,
+}
+
+/// Find similar pictures, or reuse the ones found by the last render if no palette has changed.
+/// Comparing every palette with every other one is slow for large galleries.
+fn similar_pictures(pictures: &[gallery::Picture], count: usize) -> Vec<Vec<(usize, f64)>> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&count.to_le_bytes());
+
+    for picture in pictures {
+        hasher.update(picture.id.as_bytes());
+        hasher.update(picture.color.as_bytes());
+
+        for color in &picture.palette {
+            hasher.update(color.color.as_bytes());
+            hasher.update(&color.weight.to_le_bytes());
+        }
+    }
+
+    let fingerprint = hasher.finalize().to_hex().to_string();
+    let path = match get_pictura_root_dir() {
+        Ok(dir) => dir.join(paths::similar_cache()),
+        Err(_) => return color::similar_pictures(pictures, count),
+    };
+
+    let cached = read_to_string(&path)
+        .ok()
+        .and_then(|contents| serde_json::from_str::<SimilarCache>(&contents).ok())
+        .filter(|cache| cache.fingerprint == fingerprint && cache.similar.len() == pictures.len());
+
+    if let Some(cache) = cached {
+        return cache.similar;
+    }
+
+    let cache = SimilarCache { fingerprint, similar: color::similar_pictures(pictures, count) };
+
+    match serde_json::to_string(&cache) {
+        Ok(contents) => if let Err(e) = write_atomic(&path, contents) {
+            warn!("Failed to save similar pictures: {}\n{}", path.display(), e);
+        },
+        Err(e) => warn!("Failed to save similar pictures: {}\n{}", path.display(), e),
+    }
+
+    cache.similar
+}
+
+/// Node of the category tree.
+#[derive(Serialize, Debug, Default)]
+struct Category {
+    /// Name of the directory
+    name: String,
+    /// Full category path, e.g. "Nature/Forest"
+    path: String,
+    /// Nesting level, 0 for top-level categories
+    depth: usize,
+    /// Number of pictures in this category and its subcategories
+    count: usize,
+    children: Vec<Category>,
+}
+
+/// Build a tree of nested categories from numbers of pictures by category path, sorted by name.
+fn category_tree(counts: &BTreeMap<String, usize>) -> Vec<Category> {
+    fn insert(nodes: &mut Vec<Category>, parent: Option<&str>, parts: &[&str], count: usize) {
+        let Some((name, rest)) = parts.split_first() else {
+            return;
+        };
+
+        let path = match parent {
+            Some(parent) => format!("{parent}/{name}"),
+            None => name.to_string(),
+        };
+
+        let index = match nodes.iter().position(|node| node.name == *name) {
+            Some(index) => index,
+            None => {
+                nodes.push(Category {
+                    name: name.to_string(),
+                    depth: path.matches('/').count(),
+                    path: path.clone(),
+                    ..Default::default()
+                });
+                nodes.len() - 1
+            }
+        };
+
+        nodes[index].count += count;
+        insert(&mut nodes[index].children, Some(&path), rest, count);
+    }
+
+    let mut tree = Vec::new();
+
+    // paths are sorted, so siblings are inserted in order of their names
+    for (path, count) in counts {
+        let parts: Vec<&str> = path.split('/').collect();
+        insert(&mut tree, None, &parts, *count);
+    }
+
+    tree
+}
+
+/// Sort pictures by the given key, pictures without it go last.
+/// Pictures with equal keys are ordered by name.
+fn sort_pictures(pictures: Vec<gallery::Picture>, key: SortKey, descending: bool) -> Vec<gallery::Picture> {
+    let value = |picture: &gallery::Picture| -> Option<String> {
+        let metadata = picture.metadata.clone().unwrap_or_default();
+
+        let value = match key {
+            SortKey::Name => Some(picture.name.clone()),
+            SortKey::Title => metadata.title.or_else(|| Some(picture.name.clone())),
+            SortKey::Date => metadata.date,
+            SortKey::Artist => metadata.artist,
+            SortKey::Copyright => metadata.copyright,
+            SortKey::Camera => match (metadata.make, metadata.model) {
+                (Some(make), Some(model)) => Some(format!("{make} {model}")),
+                (make, model) => make.or(model),
+            },
+            SortKey::Lens => metadata.lens,
+            SortKey::Source => metadata.source,
+        };
+
+        value.map(|value| value.to_lowercase())
+    };
+
+    let mut keyed: Vec<(Option<String>, gallery::Picture)> = pictures
+        .into_iter()
+        .map(|picture| (value(&picture), picture))
+        .collect();
+
+    keyed.sort_by(|(a_value, a), (b_value, b)| {
+        let order = match (a_value, b_value) {
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            _ if descending => b_value.cmp(a_value),
+            _ => a_value.cmp(b_value),
+        };
+
+        order.then_with(|| a.name.cmp(&b.name))
+    });
+
+    keyed.into_iter().map(|(_, picture)| picture).collect()
+}
+
+/// Size of derivatives shown on the page, if it is configured.
+const PREVIEW_SIZE: &str = "medium";
+
+/// Get the derivative shown on the page for a picture, with its fallback.
+/// If `medium` size is renamed or removed from config, the first other size is used, or the original without any.
+fn preview(picture: &gallery::Picture) -> (Option<&PathBuf>, Option<&PathBuf>) {
+    let size = [PREVIEW_SIZE]
+        .into_iter()
+        .chain(picture.derivatives.keys().map(String::as_str).filter(|size| !size.starts_with(SRCSET_PREFIX)))
+        .chain(picture.derivatives.keys().map(String::as_str))
+        .find(|size| picture.derivatives.contains_key(*size));
+
+    match size {
+        Some(size) => (picture.derivatives.get(size), picture.fallbacks.get(size)),
+        None => (picture.original.as_ref(), None),
+    }
+}
+
+/// Check if a string is an http or https URL.
+fn is_web_url(url: &str) -> bool {
+    let url = url.to_ascii_lowercase();
+
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+/// Get path relative to the generated page.
+/// Missing files, e.g. derivatives which are not generated yet, are taken as they are.
+fn relative_to_page(path: &str) -> String {
+    let page = fs::canonicalize(paths::html_file()).unwrap();
+
+    diff_paths(
+        fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path)),
+        page.parent().unwrap(),
+    ).unwrap().to_str().unwrap().to_string()
+}
+
+/// Get srcset derivatives of a picture as pairs of width and path, ordered by width.
+/// Widths larger than the picture itself produce the same image, so only the first of them is kept.
+fn srcset_entries(picture: &Value) -> Vec<(u32, &str)> {
+    let picture_width = picture["width"].as_u64().unwrap_or(0) as u32;
+
+    let mut entries: Vec<(u32, &str)> = picture["derivatives"]
+        .as_object()
+        .map(|derivatives| {
+            derivatives
+                .iter()
+                .filter_map(|(name, path)| {
+                    let width = name.strip_prefix(SRCSET_PREFIX)?.parse::<u32>().ok()?;
+
+                    Some((width.min(picture_width), path.as_str()?))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    entries.sort_by_key(|(width, _)| *width);
+    entries.dedup_by_key(|(width, _)| *width);
+
+    entries
+}
+
+/// Decode BlurHash into a tiny png with the aspect ratio of the picture and return it as a data url.
+/// Browser will upscale it smoothly, so there is no need for a bigger image.
+fn blurhash_data_url(hash: &str, width: u32, height: u32) -> Option<String> {
+    const SIZE: u32 = 16;
+
+    let (width, height) = if width >= height {
+        (SIZE, (SIZE * height / width.max(1)).max(1))
+    } else {
+        ((SIZE * width / height.max(1)).max(1), SIZE)
+    };
+
+    let pixels = blurhash::decode(hash, width, height, 1.0).ok()?;
+
+    let mut png = Vec::new();
+    PngEncoder::new(&mut png)
+        .write_image(&pixels, width, height, ColorType::Rgba8)
+        .ok()?;
+
+    Some(format!("data:image/png;base64,{}", STANDARD.encode(png)))
+}
+
 fn is_dark(hex: &str) -> bool {
     let hex = {
-        if hex.chars().next().unwrap() == '#' {
+        if hex.starts_with('#') {
             hex.chars().skip(1).collect::<String>()
         } else {
             hex.to_string()
@@ -130,5 +460,5 @@ fn is_dark(hex: &str) -> bool {
         u8::from_str_radix(&hex[4..6], 16).unwrap(),
     );
 
-    r as f32 * 0.299 + g as f32 * 0.587 + b as f32 * 0.114 <= 186 as f32
+    r as f32 * 0.299 + g as f32 * 0.587 + b as f32 * 0.114 <= 186_f32
 }
\ No newline at end of file
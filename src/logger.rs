@@ -1,8 +1,13 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use colored::Colorize;
 use log::{Level, Metadata, Record};
 
 pub static LOGGER: Logger = Logger;
 
+/// Print all messages to stderr, e.g. to keep stdout for machine-readable output.
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+
 pub struct Logger;
 
 impl log::Log for Logger {
@@ -12,28 +17,28 @@ impl log::Log for Logger {
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            match record.level() {
-                Level::Error => {
-                    eprintln!("{}: {}", "error".bold().red(), record.args())
-                }
-                Level::Warn => {
-                    println!("{}: {}", "warning".bold().yellow(), record.args())
-                }
-                Level::Info => {
-                    println!("{}: {}", "info".bold().cyan(), record.args())
-                }
-                Level::Debug => {
-                    println!("{}: {}", "debug".bold().magenta(), record.args())
-                }
-                Level::Trace => {
-                    println!("{}: {}", "trace".bold().dimmed(), record.args())
-                }
+            let label = match record.level() {
+                Level::Error => "error".bold().red(),
+                Level::Warn => "warning".bold().yellow(),
+                Level::Info => "info".bold().cyan(),
+                Level::Debug => "debug".bold().magenta(),
+                Level::Trace => "trace".bold().dimmed(),
+            };
+
+            if record.level() == Level::Error || TO_STDERR.load(Ordering::Relaxed) {
+                eprintln!("{}: {}", label, record.args())
+            } else {
+                println!("{}: {}", label, record.args())
             }
         }
     }
     fn flush(&self) {}
 }
 
+pub fn log_to_stderr() {
+    TO_STDERR.store(true, Ordering::Relaxed);
+}
+
 pub fn init_logger() {
     log::set_logger(&LOGGER).unwrap();
     log::set_max_level(log::LevelFilter::Info);
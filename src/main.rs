@@ -6,6 +6,15 @@ mod gallery;
 mod generator;
 mod logger;
 mod config;
+mod encoder;
+mod color;
+mod duplicates;
+mod metadata;
+mod sidecar;
+mod tags;
+mod doctor;
+mod migrate;
+mod watch;
 
 fn main() {
     logger::init_logger();
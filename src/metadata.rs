@@ -0,0 +1,542 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::LazyLock;
+
+use exif::{Context, Exif, Field, In, Tag, Value};
+use image::DynamicImage;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::config::MetadataPolicy;
+
+/// XMP is searched for in this many bytes at the start and at the end of a file,
+/// containers keep it either before the image data or after it.
+const XMP_SCAN_SIZE: u64 = 1024 * 1024;
+
+/// Simple XMP property written as an attribute, e.g. `tiff:Make="Canon"`.
+static XMP_ATTRIBUTE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"\s([\w.-]+:[\w.-]+)\s*=\s*"([^"]*)""#).unwrap());
+
+/// Item of an XMP list or alternative.
+static XMP_ITEM: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?s)<rdf:li(?:\s[^>]*)?>(.*?)</rdf:li>").unwrap());
+
+/// Descriptive metadata of a picture, read from EXIF, XMP and png text chunks.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    /// Camera manufacturer
+    pub make: Option<String>,
+    /// Camera model
+    pub model: Option<String>,
+    /// Lens model
+    pub lens: Option<String>,
+    /// Capture date in ISO 8601 format, e.g. "2022-07-30T18:42:05"
+    pub date: Option<String>,
+    /// Author of the picture
+    pub artist: Option<String>,
+    /// Copyright notice
+    pub copyright: Option<String>,
+    /// Where the picture comes from, e.g. the artist's page
+    pub source: Option<String>,
+    /// Title to show instead of the file name
+    pub title: Option<String>,
+    /// Longer description of the picture
+    pub description: Option<String>,
+    /// Usage terms, e.g. "CC BY 4.0"
+    pub license: Option<String>,
+    /// Keywords
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+impl Metadata {
+    /// Read metadata of an image. EXIF takes precedence over XMP, and XMP over png text chunks.
+    pub fn read(path: &Path, exif: Option<&Exif>) -> Self {
+        let from_exif = exif.map(metadata_from_exif).unwrap_or_default();
+
+        let mut file = match File::open(path) {
+            Ok(file) => BufReader::new(file),
+            Err(_) => return from_exif,
+        };
+
+        let from_xmp = read_xmp(&mut file)
+            .ok()
+            .flatten()
+            .map(|xmp| metadata_from_xmp(&xmp))
+            .unwrap_or_default();
+        let from_png = metadata_from_png(&mut file).unwrap_or_default();
+
+        from_exif.or(from_xmp).or(from_png)
+    }
+
+    /// Fill missing fields from another source.
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            make: self.make.or(other.make),
+            model: self.model.or(other.model),
+            lens: self.lens.or(other.lens),
+            date: self.date.or(other.date),
+            artist: self.artist.or(other.artist),
+            copyright: self.copyright.or(other.copyright),
+            source: self.source.or(other.source),
+            title: self.title.or(other.title),
+            description: self.description.or(other.description),
+            license: self.license.or(other.license),
+            tags: if self.tags.is_empty() { other.tags } else { self.tags },
+        }
+    }
+}
+
+/// Read EXIF metadata of an image, if it has any.
+pub fn read_exif(path: &Path) -> Option<Exif> {
+    let file = File::open(path).ok()?;
+
+    exif::Reader::new()
+        .read_from_container(&mut BufReader::new(file))
+        .ok()
+}
+
+/// Get EXIF orientation of an image, 1 means no transformation is needed.
+pub fn orientation(exif: &Exif) -> u32 {
+    exif.get_field(Tag::Orientation, In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+        .unwrap_or(1)
+}
+
+/// Rotate and flip an image according to EXIF orientation,
+/// so that it is displayed upright without relying on metadata.
+pub fn apply_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// Build EXIF metadata to embed into derivatives of an image according to the policy.
+/// Orientation is reset, since derivatives are already rotated.
+/// The thumbnail, maker notes and fields of unknown types are dropped,
+/// maker notes are often large and their internal offsets break when rewritten anyway.
+pub fn derivative_exif(exif: &Exif, policy: MetadataPolicy) -> Result<Option<Vec<u8>>, exif::Error> {
+    let upright = Field {
+        tag: Tag::Orientation,
+        ifd_num: In::PRIMARY,
+        value: Value::Short(vec![1]),
+    };
+
+    let fields: Vec<&Field> = match policy {
+        MetadataPolicy::Strip => return Ok(None),
+        MetadataPolicy::StripGps => exif.fields()
+            .filter(|field| field.tag.context() != Context::Gps)
+            .collect(),
+        MetadataPolicy::Keep => exif.fields().collect(),
+    };
+
+    let mut writer = exif::experimental::Writer::new();
+    let mut has_fields = false;
+
+    for field in fields {
+        if field.ifd_num != In::PRIMARY || field.tag == Tag::MakerNote || matches!(field.value, Value::Unknown(..)) {
+            continue;
+        }
+
+        if field.tag == Tag::Orientation {
+            writer.push_field(&upright);
+        } else {
+            writer.push_field(field);
+        }
+
+        has_fields = true;
+    }
+
+    if !has_fields {
+        return Ok(None);
+    }
+
+    let mut buf = Cursor::new(Vec::new());
+    writer.write(&mut buf, exif.little_endian())?;
+
+    Ok(Some(buf.into_inner()))
+}
+
+fn metadata_from_exif(exif: &Exif) -> Metadata {
+    let string = |tag: Tag| {
+        let field = exif.get_field(tag, In::PRIMARY)?;
+
+        match &field.value {
+            Value::Ascii(values) => values
+                .iter()
+                .map(|value| String::from_utf8_lossy(value).trim().to_string())
+                .find(|value| !value.is_empty()),
+            _ => None,
+        }
+    };
+
+    let date = [Tag::DateTimeOriginal, Tag::DateTimeDigitized, Tag::DateTime]
+        .into_iter()
+        .filter_map(string)
+        .find_map(|date| exif_date_to_iso(&date));
+
+    Metadata {
+        make: string(Tag::Make),
+        model: string(Tag::Model),
+        lens: string(Tag::LensModel),
+        date,
+        artist: string(Tag::Artist),
+        copyright: string(Tag::Copyright),
+        ..Default::default()
+    }
+}
+
+/// Convert EXIF date like "2022:07:30 18:42:05" to ISO 8601.
+fn exif_date_to_iso(date: &str) -> Option<String> {
+    let (date, time) = date.split_once(' ')?;
+    let date = date.replace(':', "-");
+
+    // unknown dates are filled with zeros or spaces
+    if date.len() != 10 || date.starts_with("0000") {
+        return None;
+    }
+
+    Some(format!("{date}T{time}"))
+}
+
+/// Read XMP packet from the start or the end of a file.
+fn read_xmp<R: Read + Seek>(reader: &mut R) -> io::Result<Option<String>> {
+    let length = reader.seek(SeekFrom::End(0))?;
+
+    let mut starts = vec![0];
+    if length > XMP_SCAN_SIZE {
+        starts.push(length - XMP_SCAN_SIZE);
+    }
+
+    for start in starts {
+        reader.seek(SeekFrom::Start(start))?;
+
+        let mut data = Vec::new();
+        reader.by_ref().take(XMP_SCAN_SIZE).read_to_end(&mut data)?;
+
+        if let Some(xmp) = find_xmp(&data) {
+            return Ok(Some(xmp.to_string()));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Find XMP packet in a part of a file. It is stored as plain text in every container.
+fn find_xmp(data: &[u8]) -> Option<&str> {
+    const START: &[u8] = b"<x:xmpmeta";
+    const END: &[u8] = b"</x:xmpmeta>";
+
+    let start = data.windows(START.len()).position(|window| window == START)?;
+    let length = data[start..].windows(END.len()).position(|window| window == END)? + END.len();
+
+    std::str::from_utf8(&data[start..start + length]).ok()
+}
+
+fn metadata_from_xmp(xmp: &str) -> Metadata {
+    let property = |names: &[&str]| names.iter().find_map(|name| xmp_property(xmp, name));
+
+    Metadata {
+        make: property(&["tiff:Make"]),
+        model: property(&["tiff:Model"]),
+        lens: property(&["exifEX:LensModel", "aux:Lens"]),
+        date: property(&["photoshop:DateCreated", "exif:DateTimeOriginal", "xmp:CreateDate"]),
+        artist: property(&["dc:creator"]),
+        copyright: property(&["dc:rights"]),
+        source: property(&["dc:source", "xmpRights:WebStatement"]),
+        title: property(&["dc:title"]),
+        description: property(&["dc:description"]),
+        license: property(&["xmpRights:UsageTerms"]),
+        tags: xmp_list(xmp, "dc:subject"),
+    }
+}
+
+/// Get a simple XMP property, written either as an attribute or as an element.
+/// For lists and alternatives the first item is taken.
+fn xmp_property(xmp: &str, name: &str) -> Option<String> {
+    let attribute = XMP_ATTRIBUTE
+        .captures_iter(xmp)
+        .find(|captures| &captures[1] == name)
+        .map(|captures| captures.get(2).unwrap().as_str());
+
+    let value = match attribute {
+        Some(value) => value,
+        None => {
+            let content = xmp_element(xmp, name)?;
+
+            match XMP_ITEM.captures(content) {
+                Some(captures) => captures.get(1).unwrap().as_str(),
+                None => content,
+            }
+        }
+    };
+
+    let value = value.trim();
+
+    // nested elements are not simple values, escaped brackets are fine
+    if value.is_empty() || value.contains('<') {
+        None
+    } else {
+        Some(unescape_xml(value))
+    }
+}
+
+/// Get all items of an XMP list property.
+fn xmp_list(xmp: &str, name: &str) -> Vec<String> {
+    let content = match xmp_element(xmp, name) {
+        Some(content) => content,
+        None => return Vec::new(),
+    };
+
+    XMP_ITEM.captures_iter(content)
+        .map(|captures| unescape_xml(captures[1].trim()))
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Get content of the first XMP element with the given name.
+fn xmp_element<'a>(xmp: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{name}");
+    let close = format!("</{name}>");
+
+    let mut rest = xmp;
+
+    loop {
+        rest = &rest[rest.find(&open)? + open.len()..];
+
+        // skip elements which only start with the name
+        if rest.starts_with('>') || rest.starts_with(char::is_whitespace) {
+            let content = &rest[rest.find('>')? + 1..];
+
+            return content.find(&close).map(|end| &content[..end]);
+        }
+    }
+}
+
+fn unescape_xml(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Read uncompressed png text chunks with well-known keywords, skipping other chunks.
+fn metadata_from_png<R: Read + Seek>(reader: &mut R) -> io::Result<Metadata> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+    let mut metadata = Metadata::default();
+
+    reader.seek(SeekFrom::Start(0))?;
+    let mut signature = [0; SIGNATURE.len()];
+    if reader.read_exact(&mut signature).is_err() || signature != SIGNATURE {
+        return Ok(metadata);
+    }
+
+    let mut header = [0; 8];
+
+    while reader.read_exact(&mut header).is_ok() {
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let kind = &header[4..8];
+
+        let text = match kind {
+            b"tEXt" | b"iTXt" => {
+                let mut chunk = Vec::new();
+                reader.by_ref().take(length.into()).read_to_end(&mut chunk)?;
+                if chunk.len() < length as usize {
+                    break;
+                }
+
+                if kind == b"tEXt" {
+                    png_text(&chunk)
+                } else {
+                    png_international_text(&chunk)
+                }
+            }
+            b"IEND" => break,
+            _ => {
+                reader.seek(SeekFrom::Current(length.into()))?;
+                None
+            }
+        };
+
+        if let Some((keyword, text)) = text {
+            let text = Some(text.trim().to_string()).filter(|text| !text.is_empty());
+
+            let field = match keyword.as_str() {
+                "Author" | "Artist" => &mut metadata.artist,
+                "Copyright" => &mut metadata.copyright,
+                "URL" | "Source URL" => &mut metadata.source,
+                // free-form by the spec, only keep dates which can be sorted
+                "Creation Time" if text.as_deref().is_some_and(is_iso_date) => &mut metadata.date,
+                _ => &mut None,
+            };
+
+            if field.is_none() {
+                *field = text;
+            }
+        }
+
+        // crc
+        reader.seek(SeekFrom::Current(4))?;
+    }
+
+    Ok(metadata)
+}
+
+/// Parse tEXt chunk: keyword, null separator and latin-1 text.
+fn png_text(chunk: &[u8]) -> Option<(String, String)> {
+    let separator = chunk.iter().position(|&b| b == 0)?;
+    let latin1 = |bytes: &[u8]| bytes.iter().map(|&b| b as char).collect::<String>();
+
+    Some((latin1(&chunk[..separator]), latin1(&chunk[separator + 1..])))
+}
+
+/// Parse uncompressed iTXt chunk: keyword, compression flag and method,
+/// language tag, translated keyword and utf-8 text, separated by nulls.
+fn png_international_text(chunk: &[u8]) -> Option<(String, String)> {
+    let mut parts = chunk.splitn(2, |&b| b == 0);
+    let keyword = String::from_utf8_lossy(parts.next()?).to_string();
+    let rest = parts.next()?;
+
+    // compressed text is rare and not worth a zlib dependency
+    if *rest.first()? != 0 {
+        return None;
+    }
+
+    let mut parts = rest.get(2..)?.splitn(3, |&b| b == 0);
+    let _language = parts.next()?;
+    let _translated = parts.next()?;
+    let text = String::from_utf8_lossy(parts.next()?).to_string();
+
+    Some((keyword, text))
+}
+
+fn is_iso_date(date: &str) -> bool {
+    let date = date.as_bytes();
+
+    date.len() >= 10
+        && date[..4].iter().all(u8::is_ascii_digit)
+        && date[4] == b'-'
+        && date[7] == b'-'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XMP: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" tiff:Make="Canon" xmpRights:WebStatement="https://example.com/a?b=1&amp;c=2">
+   <tiff:ModelName>Not the model</tiff:ModelName>
+   <tiff:Model> EOS R5 </tiff:Model>
+   <dc:creator><rdf:Seq><rdf:li>Jane Doe</rdf:li><rdf:li>John Doe</rdf:li></rdf:Seq></dc:creator>
+   <dc:title><rdf:Alt><rdf:li xml:lang="x-default">Misty &lt;Forest&gt;</rdf:li></rdf:Alt></dc:title>
+   <dc:subject><rdf:Bag><rdf:li>forest</rdf:li><rdf:li> </rdf:li><rdf:li>fog</rdf:li></rdf:Bag></dc:subject>
+   <dc:description><rdf:Alt><rdf:li xml:lang="x-default"><b>nested</b></rdf:li></rdf:Alt></dc:description>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+
+    /// Build a png file with the given chunks after the signature.
+    fn png(chunks: &[(&[u8; 4], &[u8])]) -> Cursor<Vec<u8>> {
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+
+        for (kind, chunk) in chunks {
+            data.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+            data.extend_from_slice(*kind);
+            data.extend_from_slice(chunk);
+            // crc is not checked
+            data.extend_from_slice(&[0; 4]);
+        }
+
+        Cursor::new(data)
+    }
+
+    #[test]
+    fn xmp_properties_are_read_from_attributes_and_elements() {
+        let metadata = metadata_from_xmp(XMP);
+
+        assert_eq!(metadata.make.as_deref(), Some("Canon"));
+        assert_eq!(metadata.model.as_deref(), Some("EOS R5"));
+        assert_eq!(metadata.artist.as_deref(), Some("Jane Doe"));
+        assert_eq!(metadata.title.as_deref(), Some("Misty <Forest>"));
+        assert_eq!(metadata.source.as_deref(), Some("https://example.com/a?b=1&c=2"));
+        assert_eq!(metadata.description, None);
+        assert_eq!(metadata.lens, None);
+        assert_eq!(metadata.tags, ["forest", "fog"]);
+    }
+
+    #[test]
+    fn xmp_is_found_at_start_or_end_of_file() {
+        let mut data = b"\xFF\xD8 binary ".to_vec();
+        data.extend_from_slice(XMP.as_bytes());
+        data.extend_from_slice(b" trailing");
+        assert_eq!(read_xmp(&mut Cursor::new(&data)).unwrap().as_deref(), Some(XMP));
+
+        let mut data = vec![0; 3 * XMP_SCAN_SIZE as usize];
+        data.extend_from_slice(XMP.as_bytes());
+        assert_eq!(read_xmp(&mut Cursor::new(&data)).unwrap().as_deref(), Some(XMP));
+
+        // neither at the start nor at the end
+        let mut data = vec![0; 2 * XMP_SCAN_SIZE as usize];
+        data.splice(XMP_SCAN_SIZE as usize..XMP_SCAN_SIZE as usize, XMP.bytes());
+        assert_eq!(read_xmp(&mut Cursor::new(&data)).unwrap(), None);
+
+        assert_eq!(read_xmp(&mut Cursor::new(b"<x:xmpmeta unterminated")).unwrap(), None);
+    }
+
+    #[test]
+    fn png_text_chunks_are_read() {
+        let mut data = png(&[
+            (b"IHDR", &[0; 13]),
+            (b"tEXt", b"Author\0J\xF6rg"),
+            (b"IDAT", &[1, 2, 3]),
+            (b"iTXt", b"Copyright\0\0\0en\0Urheberrecht\0\xC2\xA9 J\xC3\xB6rg"),
+            (b"tEXt", b"Creation Time\0Sat, 30 Jul 2022"),
+            (b"tEXt", b"URL\0 "),
+            (b"tEXt", b"Source URL\0https://example.com"),
+            (b"IEND", b""),
+            (b"tEXt", b"Artist\0After the end"),
+        ]);
+
+        let metadata = metadata_from_png(&mut data).unwrap();
+
+        assert_eq!(metadata.artist.as_deref(), Some("Jörg"));
+        assert_eq!(metadata.copyright.as_deref(), Some("© Jörg"));
+        assert_eq!(metadata.source.as_deref(), Some("https://example.com"));
+        assert_eq!(metadata.date, None);
+    }
+
+    #[test]
+    fn png_dates_and_compressed_text() {
+        let mut data = png(&[
+            (b"tEXt", b"Creation Time\x002022-07-30T18:42:05"),
+            (b"iTXt", b"Copyright\0\x01\0\0\0compressed"),
+        ]);
+
+        let metadata = metadata_from_png(&mut data).unwrap();
+
+        assert_eq!(metadata.date.as_deref(), Some("2022-07-30T18:42:05"));
+        assert_eq!(metadata.copyright, None);
+    }
+
+    #[test]
+    fn truncated_or_other_files_have_no_png_metadata() {
+        let mut data = png(&[(b"tEXt", b"Author\0Jane")]).into_inner();
+        data.truncate(data.len() - 8);
+        let mut data = Cursor::new(data);
+        assert_eq!(metadata_from_png(&mut data).unwrap(), Metadata::default());
+
+        let mut data = Cursor::new(b"GIF89a\x01\0\x01\0".to_vec());
+        assert_eq!(metadata_from_png(&mut data).unwrap(), Metadata::default());
+    }
+}
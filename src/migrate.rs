@@ -0,0 +1,312 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use toml::value::Table;
+use toml::Value;
+
+use crate::common::{paths, write_atomic};
+use crate::config::Config;
+use crate::gallery::{get_pictura_root_dir, GalleryLock, Pictures};
+
+/// Format version of `pictures.toml` and `config.toml` written by this version of pictura.
+/// Files without `version` key were written before versioning and have version 0.
+pub const CURRENT_VERSION: u32 = 1;
+
+/// Upgrade of a parsed file from one version to the next.
+type Migration = fn(&mut Table) -> Result<(), String>;
+
+/// Migrations of `pictures.toml`, the one at index N upgrades version N to N + 1.
+const PICTURES_MIGRATIONS: &[Migration] = &[pictures_v0_to_v1];
+
+/// Migrations of `config.toml`, the one at index N upgrades version N to N + 1.
+const CONFIG_MIGRATIONS: &[Migration] = &[config_v0_to_v1];
+
+/// Check that upgraded contents of a file can be read.
+type Check = fn(&str) -> Result<(), Box<dyn Error>>;
+
+/// Result of upgrading a file on disk.
+#[derive(Debug)]
+pub struct Migrated {
+    pub path: PathBuf,
+    /// Version of the file before the upgrade
+    pub from: u32,
+    pub to: u32,
+}
+
+/// Parse pictures file of any supported version.
+pub fn parse_pictures(contents: &str) -> Result<Pictures, Box<dyn Error>> {
+    let mut table = parse_table(contents, &paths::pictures_file())?;
+    upgrade(&mut table, PICTURES_MIGRATIONS, &paths::pictures_file())?;
+
+    Ok(Value::Table(table).try_into()?)
+}
+
+/// Parse config file of any supported version.
+pub fn parse_config(contents: &str) -> Result<Config, Box<dyn Error>> {
+    let mut table = parse_table(contents, &paths::config_file())?;
+    upgrade(&mut table, CONFIG_MIGRATIONS, &paths::config_file())?;
+
+    Ok(Value::Table(table).try_into()?)
+}
+
+/// Upgrade config and pictures files of the gallery to the current version.
+/// Returns upgraded files, files at the current version are left untouched.
+pub fn migrate() -> Result<Vec<Migrated>, Box<dyn Error>> {
+    let gallery_root = get_pictura_root_dir()?;
+    let _lock = GalleryLock::acquire(&gallery_root)?;
+
+    let mut migrated = Vec::new();
+
+    let files: [(PathBuf, &[Migration], Check); 2] = [
+        (paths::config_file(), CONFIG_MIGRATIONS, |contents| parse_config(contents).map(drop)),
+        (paths::pictures_file(), PICTURES_MIGRATIONS, |contents| parse_pictures(contents).map(drop)),
+    ];
+
+    for (file, migrations, check) in files {
+        let path = gallery_root.join(&file);
+        let Some((from, contents)) = upgrade_file(&fs::read_to_string(&path)?, migrations, &file)? else {
+            continue;
+        };
+
+        // the upgraded file is read back before it replaces the old one
+        check(&contents)?;
+        write_atomic(&path, contents)?;
+        migrated.push(Migrated { path: file, from, to: CURRENT_VERSION });
+    }
+
+    Ok(migrated)
+}
+
+/// Upgrade contents of a file to the current version.
+/// Returns the version of the file before the upgrade and the upgraded contents,
+/// or `None` if the file is at the current version already.
+fn upgrade_file(contents: &str, migrations: &[Migration], file: &Path) -> Result<Option<(u32, String)>, Box<dyn Error>> {
+    let mut original = parse_table(contents, file)?;
+    let mut table = original.clone();
+    let from = upgrade(&mut table, migrations, file)?;
+
+    if from == CURRENT_VERSION {
+        return Ok(None);
+    }
+
+    original.insert("version".to_string(), Value::Integer(CURRENT_VERSION as i64));
+
+    // rewriting the file would drop comments, so they are kept when only the version changes
+    let contents = if table == original {
+        set_version(contents, CURRENT_VERSION)
+    } else {
+        // unlike a table, a value emits plain keys before nested tables
+        toml::to_string(&Value::Table(table))?
+    };
+
+    Ok(Some((from, contents)))
+}
+
+fn parse_table(contents: &str, file: &Path) -> Result<Table, Box<dyn Error>> {
+    toml::from_str(contents).map_err(|e| format!("Invalid {}\n{}", file.display(), e).into())
+}
+
+/// Run migrations from the version of the file up to the current one and set its version.
+/// Returns the version of the file before the upgrade.
+fn upgrade(table: &mut Table, migrations: &[Migration], file: &Path) -> Result<u32, Box<dyn Error>> {
+    let version = match table.get("version") {
+        None => 0,
+        Some(Value::Integer(version)) => u32::try_from(*version)
+            .map_err(|_| format!("Invalid version of {}: {}", file.display(), version))?,
+        Some(version) => return Err(format!("Invalid version of {}: {}", file.display(), version).into()),
+    };
+
+    if version > CURRENT_VERSION {
+        return Err(format!(
+            "{} has version {}, but this pictura supports only up to version {}. Update pictura to open this gallery",
+            file.display(),
+            version,
+            CURRENT_VERSION,
+        ).into());
+    }
+
+    for (from, migration) in migrations.iter().enumerate().skip(version as usize) {
+        migration(table).map_err(|e| format!("Failed to upgrade {} from version {}\n{}", file.display(), from, e))?;
+    }
+
+    table.insert("version".to_string(), Value::Integer(CURRENT_VERSION as i64));
+
+    Ok(version)
+}
+
+/// Set top-level `version` key in the text of a toml file, keeping the rest of it as it is.
+fn set_version(contents: &str, version: u32) -> String {
+    let line = format!("version = {}", version);
+    let mut lines: Vec<&str> = contents.lines().collect();
+
+    let existing = lines
+        .iter()
+        .take_while(|l| !l.trim_start().starts_with('['))
+        .position(|l| l.split('=').next().is_some_and(|key| key.trim() == "version"));
+
+    match existing {
+        Some(index) => lines[index] = &line,
+        None => lines.insert(0, &line),
+    }
+
+    lines.join("\n") + "\n"
+}
+
+/// Pictures of 0.1.4 have random numeric ids, which are kept until sync replaces them with content hashes,
+/// and paths of `compressed` and `medium` derivatives as separate keys, which go to `derivatives`.
+fn pictures_v0_to_v1(table: &mut Table) -> Result<(), String> {
+    let Some(pictures) = table.get_mut("pictures") else {
+        return Ok(());
+    };
+
+    let pictures = pictures.as_array_mut().ok_or("`pictures` is not an array")?;
+
+    for picture in pictures {
+        let picture = picture.as_table_mut().ok_or("picture is not a table")?;
+
+        if let Some(Value::Integer(id)) = picture.get("id") {
+            let id = id.to_string();
+            picture.insert("id".to_string(), Value::String(id));
+        }
+
+        let derivatives: Table = ["compressed", "medium"]
+            .into_iter()
+            .filter_map(|size| Some((size.to_string(), picture.remove(size)?)))
+            .collect();
+
+        if !derivatives.is_empty() {
+            picture.insert("derivatives".to_string(), Value::Table(derivatives));
+        }
+    }
+
+    Ok(())
+}
+
+/// Keys added to config before versioning all have defaults, so only the version is set.
+fn config_v0_to_v1(_table: &mut Table) -> Result<(), String> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pictures and config files as written by every released version, oldest first.
+    const FIXTURES: &[(&str, &str, &str)] = &[
+        (
+            "v0-0.1.4",
+            include_str!("../tests/fixtures/v0-0.1.4/pictures.toml"),
+            include_str!("../tests/fixtures/v0-0.1.4/config.toml"),
+        ),
+        (
+            "v1",
+            include_str!("../tests/fixtures/v1/pictures.toml"),
+            include_str!("../tests/fixtures/v1/config.toml"),
+        ),
+    ];
+
+    fn fixture(name: &str) -> (&'static str, &'static str) {
+        FIXTURES
+            .iter()
+            .find(|(fixture, _, _)| *fixture == name)
+            .map(|(_, pictures, config)| (*pictures, *config))
+            .unwrap()
+    }
+
+    #[test]
+    fn every_fixture_upgrades_to_current_version() {
+        for (name, pictures, config) in FIXTURES {
+            let pictures = parse_pictures(pictures).unwrap_or_else(|e| panic!("{name}: {e}"));
+            let config = parse_config(config).unwrap_or_else(|e| panic!("{name}: {e}"));
+
+            assert_eq!(pictures.version, CURRENT_VERSION, "{name}");
+            assert_eq!(config.version, CURRENT_VERSION, "{name}");
+            assert!(!pictures.pictures.unwrap_or_default().is_empty(), "{name}");
+        }
+    }
+
+    #[test]
+    fn numeric_ids_become_strings() {
+        let pictures = parse_pictures(fixture("v0-0.1.4").0).unwrap().pictures.unwrap();
+
+        assert_eq!(pictures[0].id, "482913");
+        assert!(pictures[0].has_legacy_id());
+    }
+
+    #[test]
+    fn compressed_and_medium_move_to_derivatives() {
+        let pictures = parse_pictures(fixture("v0-0.1.4").0).unwrap().pictures.unwrap();
+
+        for picture in &pictures {
+            let derivatives = &picture.derivatives;
+
+            assert_eq!(derivatives.len(), 2, "{}", picture.name);
+            assert!(derivatives["compressed"].parent().unwrap().ends_with(".pictura/compressed"), "{}", picture.name);
+            assert!(derivatives["medium"].parent().unwrap().ends_with(".pictura/medium"), "{}", picture.name);
+        }
+    }
+
+    #[test]
+    fn current_version_is_unchanged() {
+        let (pictures, _) = fixture("v1");
+        let mut table = parse_table(pictures, &paths::pictures_file()).unwrap();
+        let original = table.clone();
+
+        assert_eq!(upgrade(&mut table, PICTURES_MIGRATIONS, &paths::pictures_file()).unwrap(), CURRENT_VERSION);
+        assert_eq!(table, original);
+    }
+
+    #[test]
+    fn upgraded_files_are_read_back() {
+        for (name, pictures, config) in FIXTURES {
+            let upgraded = upgrade_file(pictures, PICTURES_MIGRATIONS, &paths::pictures_file()).unwrap();
+            let config = upgrade_file(config, CONFIG_MIGRATIONS, &paths::config_file()).unwrap();
+
+            if *name == "v1" {
+                assert!(upgraded.is_none() && config.is_none());
+                continue;
+            }
+
+            let (from, upgraded) = upgraded.unwrap();
+            let (_, config) = config.unwrap();
+
+            assert_eq!(from, 0, "{name}");
+            assert_eq!(parse_pictures(&upgraded).unwrap().version, CURRENT_VERSION, "{name}");
+            assert!(upgraded.starts_with(&format!("version = {}", CURRENT_VERSION)), "{name}");
+            assert_eq!(parse_config(&config).unwrap().version, CURRENT_VERSION, "{name}");
+        }
+    }
+
+    #[test]
+    fn comments_are_kept_when_only_version_changes() {
+        let config = "# Edited by hand\ntitle = \"Jane's Wallpapers\"\n";
+        let (_, upgraded) = upgrade_file(config, CONFIG_MIGRATIONS, &paths::config_file()).unwrap().unwrap();
+
+        assert_eq!(upgraded, format!("version = {CURRENT_VERSION}\n{config}"));
+    }
+
+    #[test]
+    fn empty_pictures_file_is_read() {
+        let pictures = parse_pictures("").unwrap();
+
+        assert_eq!(pictures.version, CURRENT_VERSION);
+        assert!(pictures.pictures.is_none());
+    }
+
+    #[test]
+    fn newer_or_invalid_version_fails() {
+        let newer = format!("version = {}", CURRENT_VERSION + 1);
+
+        assert!(parse_pictures(&newer).unwrap_err().to_string().contains("Update pictura"));
+        assert!(parse_pictures("version = -1").is_err());
+        assert!(parse_pictures("version = \"1\"").is_err());
+    }
+
+    #[test]
+    fn version_is_set_in_text() {
+        assert_eq!(set_version("# Gallery name\ntitle = \"Walls\"\n", 1), "version = 1\n# Gallery name\ntitle = \"Walls\"\n");
+        assert_eq!(set_version("title = \"Walls\"\nversion = 0\n[srcset]\n", 1), "title = \"Walls\"\nversion = 1\n[srcset]\n");
+        assert_eq!(set_version("[derivatives.medium]\nversion = 0\n", 1), "version = 1\n[derivatives.medium]\nversion = 0\n");
+    }
+}
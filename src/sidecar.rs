@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::metadata::Metadata;
+
+/// Name of a sidecar file with metadata for all pictures of a directory.
+pub const DIRECTORY_SIDECAR: &str = "_meta.toml";
+
+/// Metadata set by user in a sidecar file.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Sidecar {
+    title: Option<String>,
+    description: Option<String>,
+    tags: Option<Vec<String>>,
+    artist: Option<String>,
+    source: Option<String>,
+    license: Option<String>,
+    /// Metadata of single pictures by file name, only used in directory sidecars
+    #[serde(default)]
+    pictures: BTreeMap<String, Sidecar>,
+}
+
+impl Sidecar {
+    fn into_metadata(self) -> Metadata {
+        Metadata {
+            title: self.title,
+            description: self.description,
+            tags: self.tags.unwrap_or_default(),
+            artist: self.artist,
+            source: self.source,
+            license: self.license,
+            ..Default::default()
+        }
+    }
+}
+
+/// Get path of the sidecar file of a picture, e.g. `forest.jpg.toml` for `forest.jpg`.
+pub fn sidecar_path(original: &Path) -> PathBuf {
+    let mut path = original.as_os_str().to_owned();
+    path.push(".toml");
+
+    PathBuf::from(path)
+}
+
+/// Get path of the directory sidecar file of a picture.
+pub fn directory_sidecar_path(original: &Path) -> PathBuf {
+    original.with_file_name(DIRECTORY_SIDECAR)
+}
+
+/// Read metadata of a picture from its sidecar files.
+/// Values of the picture's own sidecar take precedence over the ones of the directory sidecar.
+/// Returns the metadata and a fingerprint of the sidecar files used to detect their changes,
+/// or `None` if there are no sidecar files.
+pub fn read(original: &Path) -> Result<Option<(Metadata, String)>, Box<dyn Error + Send + Sync>> {
+    let own_path = sidecar_path(original);
+    let directory_path = directory_sidecar_path(original);
+
+    let own = read_file(&own_path)?;
+    let directory = read_file(&directory_path)?;
+
+    if own.is_none() && directory.is_none() {
+        return Ok(None);
+    }
+
+    let mut hasher = blake3::Hasher::new();
+    let mut parse = |path: &Path, contents: Option<String>| -> Result<Sidecar, Box<dyn Error + Send + Sync>> {
+        let contents = contents.unwrap_or_default();
+        hasher.update(&(contents.len() as u64).to_le_bytes());
+        hasher.update(contents.as_bytes());
+
+        toml::from_str(&contents).map_err(|e| format!("Invalid sidecar {}\n{}", path.display(), e).into())
+    };
+
+    let own = parse(&own_path, own)?;
+    let mut directory = parse(&directory_path, directory)?;
+
+    let file_name = original
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let picture = directory.pictures.remove(&file_name).unwrap_or_default();
+
+    let metadata = own.into_metadata()
+        .or(picture.into_metadata())
+        .or(directory.into_metadata());
+
+    Ok(Some((metadata, hasher.finalize().to_hex().to_string())))
+}
+
+fn read_file(path: &Path) -> io::Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use glob::Pattern;
+use log::warn;
+
+use crate::gallery;
+use crate::gallery::{get_pictura_root_dir, GalleryLock, Picture};
+
+/// Shortest id prefix accepted as a selector, shorter ones are too likely to clash with names.
+const MIN_ID_PREFIX: usize = 8;
+
+/// Change to the tags of pictures.
+pub enum TagChange<'a> {
+    Add(&'a [String]),
+    Remove(&'a [String]),
+}
+
+/// Add or remove tags of pictures matching the selector and regenerate the gallery.
+/// Returns number of changed pictures.
+pub fn change(selector: &str, change: TagChange) -> Result<usize, Box<dyn Error>> {
+    let gallery_root = get_pictura_root_dir()?;
+    let _lock = GalleryLock::acquire(&gallery_root)?;
+    let config = gallery::read_config(&gallery_root)?;
+    let mut pictures = gallery::read_pictures(&gallery_root)?.pictures.unwrap_or_default();
+
+    let selected = select(&pictures, selector)?;
+    let mut changed = 0;
+
+    for index in selected {
+        if let TagChange::Remove(old) = change {
+            warn_about_metadata_tags(&pictures[index], old);
+        }
+
+        let tags = &mut pictures[index].tags;
+        let before = tags.len();
+
+        match change {
+            TagChange::Add(new) => tags.extend(new.iter().map(|tag| tag.trim().to_string()).filter(|tag| !tag.is_empty())),
+            TagChange::Remove(old) => tags.retain(|tag| !old.contains(tag)),
+        }
+
+        if tags.len() != before {
+            changed += 1;
+        }
+    }
+
+    if changed > 0 {
+        gallery::write_pictures(&gallery_root, &config, pictures)?;
+    }
+
+    Ok(changed)
+}
+
+/// Get pictures matching the selector, use `Picture::all_tags` to get their tags.
+pub fn list(selector: &str) -> Result<Vec<Picture>, Box<dyn Error>> {
+    let gallery_root = get_pictura_root_dir()?;
+    let pictures = gallery::read_pictures(&gallery_root)?.pictures.unwrap_or_default();
+
+    let selected = select(&pictures, selector)?;
+
+    Ok(pictures
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| selected.contains(index))
+        .map(|(_, picture)| picture)
+        .collect())
+}
+
+/// Get all tags of the gallery with number of pictures for each.
+pub fn count() -> Result<BTreeMap<String, usize>, Box<dyn Error>> {
+    let gallery_root = get_pictura_root_dir()?;
+    let pictures = gallery::read_pictures(&gallery_root)?.pictures.unwrap_or_default();
+
+    let mut tags: BTreeMap<String, usize> = BTreeMap::new();
+    for tag in pictures.iter().flat_map(|picture| picture.all_tags()) {
+        *tags.entry(tag).or_default() += 1;
+    }
+
+    Ok(tags)
+}
+
+/// Tags from sidecars and embedded metadata are not stored in the pictures file,
+/// so removing them has to be done at their source.
+fn warn_about_metadata_tags(picture: &Picture, removed: &[String]) {
+    let metadata_tags = match &picture.metadata {
+        Some(metadata) => &metadata.tags,
+        None => return,
+    };
+
+    for tag in removed.iter().filter(|tag| metadata_tags.contains(tag)) {
+        warn!(
+            "Tag {tag} of {} comes from its sidecar file or embedded metadata, remove it there instead",
+            picture.name
+        );
+    }
+}
+
+/// Find pictures by id (or its prefix), name or glob pattern.
+/// Exact matches take precedence, so names with pattern characters like `[` can be selected as they are.
+/// Patterns are matched against names and against category paths with file names, e.g. "Nature/*.jpg".
+fn select(pictures: &[Picture], selector: &str) -> Result<Vec<usize>, Box<dyn Error>> {
+    let matching = |matches: &dyn Fn(&Picture, &str) -> bool| -> Vec<usize> {
+        pictures
+            .iter()
+            .enumerate()
+            .filter(|(_, picture)| matches(picture, &category_path(picture)))
+            .map(|(index, _)| index)
+            .collect()
+    };
+
+    let selected = matching(&|picture, path| {
+        (selector.len() >= MIN_ID_PREFIX && picture.id.starts_with(selector))
+            || picture.name == selector
+            || path == selector
+    });
+
+    if !selected.is_empty() {
+        return Ok(selected);
+    }
+
+    let pattern = Pattern::new(selector).map_err(|e| format!("No pictures match {selector}, and it is not a valid pattern: {e}"))?;
+    let selected = matching(&|picture, path| pattern.matches(&picture.name) || pattern.matches(path));
+
+    if selected.is_empty() {
+        return Err(format!("No pictures match {selector}").into());
+    }
+
+    Ok(selected)
+}
+
+/// Category path with file name, e.g. "Nature/forest.jpg".
+fn category_path(picture: &Picture) -> String {
+    let file_name = format!("{}.{}", picture.name, picture.extension);
+
+    match &picture.category {
+        Some(category) => format!("{category}/{file_name}"),
+        None => file_name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn picture(name: &str, category: &str, id: &str) -> Picture {
+        toml::from_str(&format!(
+            r#"
+            name = "{name}"
+            extension = "jpg"
+            category = "{category}"
+            width = 64
+            height = 48
+            color = "18172c"
+            id = "{id}"
+            "#
+        )).unwrap()
+    }
+
+    fn pictures() -> Vec<Picture> {
+        vec![
+            picture("forest", "Nature", "1fad991b5d5ea5bfdb3e1b3b7d7655f926749cb06669bae1feaa7c46d7327157"),
+            picture("forest [old]", "Nature", "77c0ffee5d5ea5bfdb3e1b3b7d7655f926749cb06669bae1feaa7c46d7327157"),
+            picture("city", "Urban", "9b3e1b3b7d7655f926749cb06669bae1feaa7c46d73271571fad991b5d5ea5bf"),
+        ]
+    }
+
+    #[test]
+    fn exact_matches_take_precedence_over_patterns() {
+        let pictures = pictures();
+
+        assert_eq!(select(&pictures, "forest [old]").unwrap(), [1]);
+        assert_eq!(select(&pictures, "forest").unwrap(), [0]);
+        assert_eq!(select(&pictures, "Urban/city.jpg").unwrap(), [2]);
+        assert_eq!(select(&pictures, "77c0ffee").unwrap(), [1]);
+    }
+
+    #[test]
+    fn patterns_match_names_and_paths() {
+        let pictures = pictures();
+
+        assert_eq!(select(&pictures, "forest*").unwrap(), [0, 1]);
+        assert_eq!(select(&pictures, "Urban/*").unwrap(), [2]);
+        // too short to be taken as an id
+        assert!(select(&pictures, "77c0").is_err());
+        assert!(select(&pictures, "forest [").is_err());
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use log::{error, info, warn};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::common::paths;
+use crate::gallery;
+use crate::gallery::{get_pictura_root_dir, SyncOptions};
+
+/// Time without new events after which the collected changes are synced.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Sync the gallery, then sync it again on every change of source directories, config or template.
+/// Changed files are synced incrementally, while config changes trigger a full sync. Runs until interrupted.
+pub fn watch(jobs: usize) -> Result<(), Box<dyn Error>> {
+    let gallery_root = get_pictura_root_dir()?;
+    let pictura_dir = gallery_root.join(paths::pictura());
+    let config_file = gallery_root.join(paths::config_file());
+    let template_file = gallery_root.join(paths::template_file());
+
+    gallery::sync(&SyncOptions { jobs, ..Default::default() })?;
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+
+    // editors often replace files instead of writing them, so the directory is watched
+    watcher.watch(&pictura_dir, RecursiveMode::NonRecursive)?;
+    let mut sources = watch_sources(&mut watcher, &gallery_root, Vec::new());
+
+    info!("Watching for changes, press Ctrl+C to stop");
+
+    while let Some(changed) = receive_changes(&receiver) {
+        let config_changed = changed.contains(&config_file);
+        let template_changed = changed.contains(&template_file);
+
+        // files written by sync itself
+        let changed: HashSet<PathBuf> = changed
+            .into_iter()
+            .filter(|path| !path.starts_with(&pictura_dir) && *path != gallery_root.join(paths::html_file()))
+            .collect();
+
+        if !config_changed && !template_changed && changed.is_empty() {
+            continue;
+        }
+
+        let options = SyncOptions {
+            jobs,
+            changed: if config_changed { None } else { Some(changed) },
+            ..Default::default()
+        };
+
+        if let Err(e) = gallery::sync(&options) {
+            error!("{}", e);
+        }
+
+        if config_changed {
+            sources = watch_sources(&mut watcher, &gallery_root, sources);
+        }
+    }
+
+    Ok(())
+}
+
+/// Watch source directories from the config instead of the previously watched ones.
+/// Returns the watched directories, which are kept as they are if the config can't be read.
+fn watch_sources(watcher: &mut RecommendedWatcher, gallery_root: &Path, watched: Vec<PathBuf>) -> Vec<PathBuf> {
+    let config = match gallery::read_config(gallery_root) {
+        Ok(config) => config,
+        Err(e) => {
+            warn!("Failed to read config, source directories are not updated\n{}", e);
+            return watched;
+        }
+    };
+
+    for path in &watched {
+        if let Err(e) = watcher.unwatch(path) {
+            warn!("Failed to stop watching {}\n{}", path.display(), e);
+        }
+    }
+
+    config
+        .sources(gallery_root)
+        .into_iter()
+        .map(|source| source.path)
+        .filter(|path| match watcher.watch(path, RecursiveMode::Recursive) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Failed to watch {}\n{}", path.display(), e);
+                false
+            }
+        })
+        .collect()
+}
+
+/// Wait for filesystem events and collect paths they touch until no events arrive for `DEBOUNCE`.
+/// Returns `None` when the watcher is gone.
+fn receive_changes(receiver: &Receiver<notify::Result<Event>>) -> Option<HashSet<PathBuf>> {
+    let mut events = vec![receiver.recv().ok()?];
+
+    loop {
+        match receiver.recv_timeout(DEBOUNCE) {
+            Ok(event) => events.push(event),
+            Err(RecvTimeoutError::Timeout) => break,
+            Err(RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+
+    let mut changed = HashSet::new();
+
+    for event in events {
+        match event {
+            Ok(event) if !event.kind.is_access() => changed.extend(event.paths),
+            Ok(_) => {}
+            Err(e) => warn!("Failed to watch for changes\n{}", e),
+        }
+    }
+
+    Some(changed)
+}